@@ -0,0 +1,92 @@
+use crate::{err, Req, Response, Status, Verb};
+use std::time::Duration;
+
+pub struct Cors {
+    origins: Vec<String>,
+    methods: Vec<Verb>,
+    headers: Vec<String>,
+    max_age: Option<u64>,
+}
+
+impl Default for Cors {
+    fn default() -> Cors {
+        Cors {
+            origins: Vec::new(),
+            methods: vec![Verb::Get, Verb::Head, Verb::Post],
+            headers: Vec::new(),
+            max_age: None,
+        }
+    }
+}
+
+impl Cors {
+    pub fn new() -> Cors {
+        Cors::default()
+    }
+
+    pub fn allow_origin(mut self, origin: &str) -> Cors {
+        self.origins.push(origin.to_string());
+        self
+    }
+
+    pub fn allow_methods(mut self, methods: &[Verb]) -> Cors {
+        self.methods = methods.to_vec();
+        self
+    }
+
+    pub fn allow_headers(mut self, headers: &[&str]) -> Cors {
+        self.headers = headers.iter().map(|h| h.to_string()).collect();
+        self
+    }
+
+    pub fn max_age(mut self, max_age: Duration) -> Cors {
+        self.max_age = Some(max_age.as_secs());
+        self
+    }
+
+    fn allowed_origin(&self, req: &Req) -> Option<String> {
+        let origin = req.header("Origin")?;
+        if self.origins.iter().any(|o| o == "*") {
+            return Some("*".to_string());
+        }
+        self.origins
+            .iter()
+            .find(|o| o.eq_ignore_ascii_case(origin))
+            .map(|_| origin.to_string())
+    }
+
+    pub fn is_preflight(&self, req: &Req) -> bool {
+        req.verb == Verb::Options
+            && req.header("Origin").is_some()
+            && req.header("Access-Control-Request-Method").is_some()
+    }
+
+    pub fn headers(&self, req: &Req) -> Vec<(String, String)> {
+        let mut headers = Vec::new();
+        if let Some(origin) = self.allowed_origin(req) {
+            if origin != "*" {
+                headers.push(("Vary".to_string(), "Origin".to_string()));
+            }
+            headers.push(("Access-Control-Allow-Origin".to_string(), origin));
+        }
+        headers
+    }
+
+    pub fn preflight(&self, req: &Req, mut client: impl std::io::Write) -> err::Result<()> {
+        let mut resp = Response::new(Status::NoContent);
+        for (name, value) in self.headers(req) {
+            resp = resp.header(&name, &value);
+        }
+        if self.allowed_origin(req).is_some() {
+            let methods: Vec<_> = self.methods.iter().map(|m| m.to_string()).collect();
+            resp = resp.header("Access-Control-Allow-Methods", &methods.join(", "));
+            if !self.headers.is_empty() {
+                resp = resp.header("Access-Control-Allow-Headers", &self.headers.join(", "));
+            }
+            if let Some(max_age) = self.max_age {
+                resp = resp.header("Access-Control-Max-Age", &max_age.to_string());
+            }
+        }
+        resp.write_to(&mut client)
+    }
+}
@@ -0,0 +1,519 @@
+use crate::err;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+const WINDOW: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const MAX_CHAIN: usize = 64;
+const HASH_BITS: u32 = 15;
+
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u32,
+    count: u32,
+}
+
+impl BitWriter {
+    fn new() -> BitWriter {
+        BitWriter {
+            out: Vec::new(),
+            bits: 0,
+            count: 0,
+        }
+    }
+
+    fn bits(&mut self, value: u32, count: u32) {
+        self.bits |= value << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    // Huffman codes are packed starting from their most significant bit.
+    fn code(&mut self, code: u32, len: u32) {
+        let mut rev = 0;
+        for i in 0..len {
+            rev |= ((code >> i) & 1) << (len - 1 - i);
+        }
+        self.bits(rev, len);
+    }
+
+    fn align(&mut self) {
+        if self.count > 0 {
+            self.out.push(self.bits as u8);
+            self.bits = 0;
+            self.count = 0;
+        }
+    }
+}
+
+fn fixed_literal(w: &mut BitWriter, sym: u32) {
+    match sym {
+        0..=143 => w.code(0x30 + sym, 8),
+        144..=255 => w.code(0x190 + sym - 144, 9),
+        256..=279 => w.code(sym - 256, 7),
+        _ => w.code(0xc0 + sym - 280, 8),
+    }
+}
+
+fn fixed_match(w: &mut BitWriter, len: usize, dist: usize) {
+    let i = LENGTH_BASE
+        .iter()
+        .rposition(|b| *b as usize <= len)
+        .unwrap();
+    fixed_literal(w, 257 + i as u32);
+    w.bits(
+        (len - LENGTH_BASE[i] as usize) as u32,
+        LENGTH_EXTRA[i] as u32,
+    );
+
+    let i = DIST_BASE.iter().rposition(|b| *b as usize <= dist).unwrap();
+    w.code(i as u32, 5);
+    w.bits((dist - DIST_BASE[i] as usize) as u32, DIST_EXTRA[i] as u32);
+}
+
+// Hash chains for finding matches. Positions are stored offset by `base`,
+// which moves past each input in turn, so entries left over from earlier
+// inputs read as empty and the tables are reused without being cleared.
+struct Matcher {
+    head: Vec<usize>,
+    prev: Vec<usize>,
+    base: usize,
+}
+
+impl Matcher {
+    fn new() -> Matcher {
+        Matcher {
+            head: vec![0; 1 << HASH_BITS],
+            prev: Vec::new(),
+            base: 1,
+        }
+    }
+
+    fn start(&mut self, data: &[u8]) {
+        if self.prev.len() < data.len() {
+            self.prev.resize(data.len(), 0);
+        }
+    }
+
+    fn finish(&mut self, data: &[u8]) {
+        self.base += data.len();
+    }
+
+    fn hash(data: &[u8], pos: usize) -> Option<usize> {
+        let d = data.get(pos..pos + MIN_MATCH)?;
+        let v = (d[0] as u32) << 16 | (d[1] as u32) << 8 | d[2] as u32;
+        Some((v.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize)
+    }
+
+    fn insert(&mut self, data: &[u8], pos: usize) {
+        if let Some(h) = Matcher::hash(data, pos) {
+            self.prev[pos] = self.head[h];
+            self.head[h] = self.base + pos;
+        }
+    }
+
+    fn longest(&self, data: &[u8], pos: usize) -> (usize, usize) {
+        let mut best = (0, 0);
+        let Some(h) = Matcher::hash(data, pos) else {
+            return best;
+        };
+        let max = (data.len() - pos).min(MAX_MATCH);
+        let mut entry = self.head[h];
+        let mut chain = 0;
+        while entry >= self.base && chain < MAX_CHAIN {
+            let cand = entry - self.base;
+            if pos - cand > WINDOW {
+                break;
+            }
+            let len = data[cand..]
+                .iter()
+                .zip(&data[pos..pos + max])
+                .take_while(|(a, b)| a == b)
+                .count();
+            if len > best.0 {
+                best = (len, pos - cand);
+                if len == max {
+                    break;
+                }
+            }
+            entry = self.prev[cand];
+            chain += 1;
+        }
+        best
+    }
+}
+
+fn fixed_block(w: &mut BitWriter, m: &mut Matcher, data: &[u8], last: bool) {
+    w.bits(last as u32, 1);
+    w.bits(1, 2);
+
+    m.start(data);
+    let mut pos = 0;
+    while pos < data.len() {
+        let (len, dist) = m.longest(data, pos);
+        if len >= MIN_MATCH {
+            fixed_match(w, len, dist);
+            for p in pos..pos + len {
+                m.insert(data, p);
+            }
+            pos += len;
+        } else {
+            fixed_literal(w, data[pos] as u32);
+            m.insert(data, pos);
+            pos += 1;
+        }
+    }
+    m.finish(data);
+    fixed_literal(w, 256);
+}
+
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut w = BitWriter::new();
+    fixed_block(&mut w, &mut Matcher::new(), data, true);
+    w.align();
+    w.out
+}
+
+#[cfg(test)]
+pub fn compress_sync(data: &[u8]) -> Vec<u8> {
+    Compressor::new().compress_sync(data)
+}
+
+// Compresses a run of inputs such as the messages on one connection, each
+// ending with a sync flush (an empty stored block) rather than a final block
+// so the stream can carry on or be cut there. The match tables are kept from
+// one call to the next.
+pub struct Compressor {
+    matcher: Matcher,
+}
+
+impl Default for Compressor {
+    fn default() -> Compressor {
+        Compressor::new()
+    }
+}
+
+impl Compressor {
+    pub fn new() -> Compressor {
+        Compressor {
+            matcher: Matcher::new(),
+        }
+    }
+
+    pub fn compress_sync(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut w = BitWriter::new();
+        fixed_block(&mut w, &mut self.matcher, data, false);
+        w.bits(0, 3);
+        w.align();
+        w.out.extend_from_slice(&[0, 0, 0xff, 0xff]);
+        w.out
+    }
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bits: u32,
+    count: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn bit(&mut self) -> err::Result<u32> {
+        if self.count == 0 {
+            let Some(b) = self.data.get(self.pos) else {
+                return err::input("deflate stream truncated".to_string());
+            };
+            self.pos += 1;
+            self.bits = *b as u32;
+            self.count = 8;
+        }
+        let bit = self.bits & 1;
+        self.bits >>= 1;
+        self.count -= 1;
+        Ok(bit)
+    }
+
+    fn bits(&mut self, count: u32) -> err::Result<u32> {
+        let mut v = 0;
+        for i in 0..count {
+            v |= self.bit()? << i;
+        }
+        Ok(v)
+    }
+
+    fn bytes(&mut self, len: usize) -> err::Result<&'a [u8]> {
+        self.count = 0;
+        let Some(b) = self.data.get(self.pos..self.pos + len) else {
+            return err::input("deflate stream truncated".to_string());
+        };
+        self.pos += len;
+        Ok(b)
+    }
+}
+
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Huffman {
+        let mut counts = [0u16; 16];
+        for l in lengths {
+            counts[*l as usize] += 1;
+        }
+        counts[0] = 0;
+
+        let mut offsets = [0u16; 16];
+        for i in 1..16 {
+            offsets[i] = offsets[i - 1] + counts[i - 1];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (sym, l) in lengths.iter().enumerate() {
+            if *l != 0 {
+                symbols[offsets[*l as usize] as usize] = sym as u16;
+                offsets[*l as usize] += 1;
+            }
+        }
+        Huffman { counts, symbols }
+    }
+
+    fn decode(&self, r: &mut BitReader) -> err::Result<u16> {
+        let mut code = 0i32;
+        let mut first = 0i32;
+        let mut index = 0i32;
+        for len in 1..16 {
+            code |= r.bit()? as i32;
+            let count = self.counts[len] as i32;
+            if code - count < first {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first += count;
+            first <<= 1;
+            code <<= 1;
+        }
+        err::input("invalid huffman code".to_string())
+    }
+}
+
+fn fixed_tables() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; 288];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    (Huffman::new(&lengths), Huffman::new(&[5; 30]))
+}
+
+fn dynamic_tables(r: &mut BitReader) -> err::Result<(Huffman, Huffman)> {
+    let nlen = r.bits(5)? as usize + 257;
+    let ndist = r.bits(5)? as usize + 1;
+    let ncode = r.bits(4)? as usize + 4;
+
+    let mut lengths = [0u8; 19];
+    for i in CODE_LENGTH_ORDER.iter().take(ncode) {
+        lengths[*i] = r.bits(3)? as u8;
+    }
+    let codes = Huffman::new(&lengths);
+
+    let mut lengths = vec![0u8; nlen + ndist];
+    let mut i = 0;
+    while i < nlen + ndist {
+        let sym = codes.decode(r)?;
+        let (value, repeat) = match sym {
+            0..=15 => (sym as u8, 1),
+            16 if i > 0 => (lengths[i - 1], 3 + r.bits(2)?),
+            17 => (0, 3 + r.bits(3)?),
+            18 => (0, 11 + r.bits(7)?),
+            _ => return err::input("invalid code lengths".to_string()),
+        };
+        for _ in 0..repeat {
+            if i == lengths.len() {
+                return err::input("invalid code lengths".to_string());
+            }
+            lengths[i] = value;
+            i += 1;
+        }
+    }
+    Ok((
+        Huffman::new(&lengths[..nlen]),
+        Huffman::new(&lengths[nlen..]),
+    ))
+}
+
+fn inflate_block(
+    r: &mut BitReader,
+    out: &mut Vec<u8>,
+    lit: &Huffman,
+    dist: &Huffman,
+    limit: usize,
+) -> err::Result<()> {
+    loop {
+        let sym = lit.decode(r)? as usize;
+        if sym < 256 {
+            if out.len() == limit {
+                return err::too_large(format!("inflated data over {} bytes", limit));
+            }
+            out.push(sym as u8);
+            continue;
+        }
+        if sym == 256 {
+            return Ok(());
+        }
+
+        let i = sym - 257;
+        if i >= LENGTH_BASE.len() {
+            return err::input("invalid length code".to_string());
+        }
+        let len = LENGTH_BASE[i] as usize + r.bits(LENGTH_EXTRA[i] as u32)? as usize;
+
+        let i = dist.decode(r)? as usize;
+        if i >= DIST_BASE.len() {
+            return err::input("invalid distance code".to_string());
+        }
+        let d = DIST_BASE[i] as usize + r.bits(DIST_EXTRA[i] as u32)? as usize;
+        if d > out.len() {
+            return err::input("distance too far back".to_string());
+        }
+        if len > limit - out.len() {
+            return err::too_large(format!("inflated data over {} bytes", limit));
+        }
+
+        let start = out.len() - d;
+        for k in 0..len {
+            out.push(out[start + k]);
+        }
+    }
+}
+
+#[cfg(test)]
+pub fn inflate(data: &[u8]) -> err::Result<Vec<u8>> {
+    inflate_blocks(data, Vec::new(), usize::MAX, false)
+}
+
+// Inflates a stream cut at a sync flush, stopping when the input runs out at
+// the end of a block. Back-references may reach into `dict`, the tail of
+// earlier output, which is not part of what's returned. Producing more than
+// `limit` bytes fails with `TooLarge`.
+pub fn inflate_sync(data: &[u8], dict: &[u8], limit: usize) -> err::Result<Vec<u8>> {
+    let mut out = inflate_blocks(data, dict.to_vec(), dict.len().saturating_add(limit), true)?;
+    out.drain(..dict.len());
+    Ok(out)
+}
+
+fn inflate_blocks(data: &[u8], mut out: Vec<u8>, limit: usize, sync: bool) -> err::Result<Vec<u8>> {
+    let mut r = BitReader {
+        data,
+        pos: 0,
+        bits: 0,
+        count: 0,
+    };
+    loop {
+        let last = r.bit()? == 1;
+        match r.bits(2)? {
+            0 => {
+                let hdr = r.bytes(4)?;
+                let len = u16::from_le_bytes([hdr[0], hdr[1]]);
+                let nlen = u16::from_le_bytes([hdr[2], hdr[3]]);
+                if len != !nlen {
+                    return err::input("stored block length mismatch".to_string());
+                }
+                if len as usize > limit - out.len() {
+                    return err::too_large(format!("inflated data over {} bytes", limit));
+                }
+                out.extend_from_slice(r.bytes(len as usize)?);
+            }
+            1 => {
+                let (lit, dist) = fixed_tables();
+                inflate_block(&mut r, &mut out, &lit, &dist, limit)?;
+            }
+            2 => {
+                let (lit, dist) = dynamic_tables(&mut r)?;
+                inflate_block(&mut r, &mut out, &lit, &dist, limit)?;
+            }
+            _ => return err::input("invalid block type".to_string()),
+        }
+        if last || (sync && r.pos == data.len()) {
+            return Ok(out);
+        }
+    }
+}
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut c = n as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 {
+                0xedb88320 ^ (c >> 1)
+            } else {
+                c >> 1
+            };
+            k += 1;
+        }
+        table[n] = c;
+        n += 1;
+    }
+    table
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut c = !0u32;
+    for b in data {
+        c = CRC_TABLE[((c ^ *b as u32) & 0xff) as usize] ^ (c >> 8);
+    }
+    !c
+}
+
+pub fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+    out.extend(compress(data));
+    out.extend(crc32(data).to_le_bytes());
+    out.extend((data.len() as u32).to_le_bytes());
+    out
+}
+
+#[cfg(test)]
+pub fn gunzip(data: &[u8]) -> err::Result<Vec<u8>> {
+    if data.len() < 18 || data[..3] != [0x1f, 0x8b, 8] {
+        return err::input("not a gzip stream".to_string());
+    }
+    if data[3] != 0 {
+        return err::input("unsupported gzip flags".to_string());
+    }
+
+    let trailer = &data[data.len() - 8..];
+    let out = inflate(&data[10..data.len() - 8])?;
+    let crc = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+    if crc != crc32(&out) {
+        return err::input("gzip checksum mismatch".to_string());
+    }
+    Ok(out)
+}
@@ -1,28 +1,61 @@
 #[derive(Debug)]
 pub enum Error {
-    Io(String),
+    Io(std::io::Error),
     Input(String),
+    TooLarge(String),
+    Utf8(String),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+impl Error {
+    pub fn kind(&self) -> Option<std::io::ErrorKind> {
+        match self {
+            Error::Io(e) => Some(e.kind()),
+            _ => None,
+        }
+    }
+
+    pub fn is_not_found(&self) -> bool {
+        self.kind() == Some(std::io::ErrorKind::NotFound)
+    }
+
+    pub fn is_timeout(&self) -> bool {
+        matches!(
+            self.kind(),
+            Some(std::io::ErrorKind::WouldBlock) | Some(std::io::ErrorKind::TimedOut)
+        )
+    }
+
+    pub fn is_disconnect(&self) -> bool {
+        matches!(
+            self.kind(),
+            Some(std::io::ErrorKind::BrokenPipe)
+                | Some(std::io::ErrorKind::ConnectionReset)
+                | Some(std::io::ErrorKind::ConnectionAborted)
+        )
+    }
+}
+
 impl From<std::io::Error> for Error {
     fn from(e: std::io::Error) -> Error {
-        Error::Io(e.to_string())
+        Error::Io(e)
     }
 }
 
 impl From<std::string::FromUtf8Error> for Error {
     fn from(e: std::string::FromUtf8Error) -> Error {
-        Error::Io(e.to_string())
+        Error::Utf8(e.to_string())
     }
 }
 
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Error::Io(msg) => write!(f, "Error::Io({})", msg),
+            Error::Io(e) => write!(f, "Error::Io({})", e),
             Error::Input(msg) => write!(f, "Error::Input({})", msg),
+            Error::TooLarge(msg) => write!(f, "Error::TooLarge({})", msg),
+            Error::Utf8(msg) => write!(f, "Error::Utf8({})", msg),
         }
     }
 }
@@ -30,3 +63,7 @@ impl std::fmt::Display for Error {
 pub fn input<T>(msg: String) -> Result<T> {
     Err(Error::Input(msg))
 }
+
+pub fn too_large<T>(msg: String) -> Result<T> {
+    Err(Error::TooLarge(msg))
+}
@@ -0,0 +1,469 @@
+use crate::{copy_body, err, Status, Stream};
+use std::io::{Cursor, IoSlice, Read, Write};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+// Past this, what is being written is not a response head we understand.
+const MAX_HEAD: usize = 64 * 1024;
+
+// The connection as handed to a handler for one request. It follows the
+// response through the bytes written to it, so it knows what has been sent
+// however that was written: with Response, by hand, or from another thread
+// through a clone, as clones share the one record.
+pub(crate) struct Exchange {
+    inner: Box<dyn Stream>,
+    state: ResponseState,
+    // what the server read past the request, to be read before the rest
+    read_ahead: Arc<Mutex<Cursor<Vec<u8>>>>,
+    // one per handle on the exchange, to tell when the handler let go
+    handles: Arc<()>,
+}
+
+#[derive(Clone)]
+pub(crate) struct ResponseState(Arc<Mutex<Progress>>);
+
+struct Progress {
+    // answering HEAD, so the body is followed but not sent
+    head_only: bool,
+    // whether the connection may carry another request after this one, and
+    // whether the client needs telling so because it speaks HTTP/1.0
+    keep_alive: bool,
+    http_1_0: bool,
+    // the Server header, unless the response has its own
+    server: Option<String>,
+    // headers for the response added by others than its writer
+    extra: Vec<(String, String)>,
+    // a head written in pieces, held back until it's whole
+    head: Vec<u8>,
+    status: Option<Status>,
+    body: Framing,
+    sent: u64,
+}
+
+enum Framing {
+    // no final response head yet
+    Head,
+    Length(u64),
+    Chunked(Chunks),
+    UntilClose,
+    Upgraded,
+    Done,
+}
+
+impl Exchange {
+    pub(crate) fn new(inner: Box<dyn Stream>, server: Option<&str>, head_only: bool) -> Exchange {
+        Exchange {
+            inner,
+            state: ResponseState(Arc::new(Mutex::new(Progress {
+                head_only,
+                keep_alive: false,
+                http_1_0: false,
+                server: server.map(|s| s.to_string()),
+                extra: Vec::new(),
+                head: Vec::new(),
+                status: None,
+                body: Framing::Head,
+                sent: 0,
+            }))),
+            read_ahead: Arc::new(Mutex::new(Cursor::new(Vec::new()))),
+            handles: Arc::new(()),
+        }
+    }
+
+    pub(crate) fn read_ahead(self, buf: Vec<u8>) -> Exchange {
+        *self.read_ahead.lock().unwrap_or_else(|e| e.into_inner()) = Cursor::new(buf);
+        self
+    }
+
+    // Lets the connection be reused once the response is sent, which the
+    // head says unless it already has a Connection header of its own.
+    pub(crate) fn keep_alive(self, version: &str) -> Exchange {
+        let mut p = self.state.lock();
+        p.keep_alive = true;
+        p.http_1_0 = version == "HTTP/1.0";
+        drop(p);
+        self
+    }
+
+    pub(crate) fn state(&self) -> ResponseState {
+        self.state.clone()
+    }
+
+    pub(crate) fn duplicate(&self) -> std::io::Result<Exchange> {
+        Ok(Exchange {
+            inner: self.inner.try_clone()?,
+            state: self.state.clone(),
+            read_ahead: self.read_ahead.clone(),
+            handles: self.handles.clone(),
+        })
+    }
+
+    // Whether no other handle on the exchange is left, so nobody else can
+    // still answer the request.
+    pub(crate) fn last_handle(&self) -> bool {
+        Arc::strong_count(&self.handles) == 1
+    }
+}
+
+impl ResponseState {
+    fn lock(&self) -> MutexGuard<'_, Progress> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // The status and size of the response, for the access log.
+    pub(crate) fn response(&self) -> Option<(Status, Option<u64>)> {
+        let p = self.lock();
+        let bytes = match p.body {
+            Framing::Upgraded => None,
+            _ => Some(p.sent),
+        };
+        p.status.map(|status| (status, bytes))
+    }
+
+    // Whether a response can still be sent. Part of a head not yet finished
+    // is dropped, so that one can.
+    pub(crate) fn unanswered(&self) -> bool {
+        let mut p = self.lock();
+        if !matches!(p.body, Framing::Head) {
+            return false;
+        }
+        p.head.clear();
+        true
+    }
+
+    // Whether the response has been sent in full and the connection can
+    // carry another. Only the head of a response to HEAD is sent.
+    pub(crate) fn reusable(&self) -> bool {
+        let p = self.lock();
+        if !p.keep_alive {
+            return false;
+        }
+        match p.body {
+            Framing::Done => true,
+            Framing::Head | Framing::Upgraded => false,
+            _ => p.head_only,
+        }
+    }
+}
+
+fn already_sent(status: Option<Status>) -> std::io::Error {
+    let msg = match status {
+        Some(status) => format!("response already sent: {}", status),
+        None => "response already sent".to_string(),
+    };
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, msg)
+}
+
+fn parse_status(line: &str) -> Option<Status> {
+    let mut parts = line.splitn(3, ' ');
+    match parts.next()? {
+        "HTTP/1.0" | "HTTP/1.1" => {}
+        _ => return None,
+    }
+    let code = parts.next()?.parse::<u16>().ok()?;
+    (100..=999).contains(&code).then(|| Status::from_code(code))
+}
+
+impl Progress {
+    fn write(&mut self, out: &mut dyn Write, buf: &[u8]) -> std::io::Result<usize> {
+        let (n, done) = match &mut self.body {
+            Framing::Head => return self.write_head(out, buf),
+            Framing::Length(left) => {
+                let n = (*left).min(buf.len() as u64);
+                *left -= n;
+                (n as usize, *left == 0)
+            }
+            Framing::Chunked(chunks) => match chunks.advance(buf) {
+                Some(progress) => progress,
+                // not chunked after all, so there's no telling where it ends
+                None => {
+                    self.body = Framing::UntilClose;
+                    (buf.len(), false)
+                }
+            },
+            Framing::UntilClose | Framing::Upgraded => (buf.len(), false),
+            Framing::Done if buf.is_empty() => return Ok(0),
+            Framing::Done => return Err(already_sent(self.status)),
+        };
+        if !self.head_only {
+            out.write_all(&buf[..n])?;
+            self.sent += n as u64;
+        }
+        if done {
+            self.body = Framing::Done;
+        }
+        Ok(n)
+    }
+
+    // A head from Response, which may only start the response.
+    fn write_response_head(&mut self, out: &mut dyn Write, buf: &[u8]) -> std::io::Result<usize> {
+        match self.body {
+            Framing::Head | Framing::Upgraded => self.write(out, buf),
+            _ => Err(already_sent(self.status)),
+        }
+    }
+
+    fn write_head(&mut self, out: &mut dyn Write, buf: &[u8]) -> std::io::Result<usize> {
+        for (i, b) in buf.iter().enumerate() {
+            self.head.push(*b);
+            if self.head.ends_with(b"\n\n") || self.head.ends_with(b"\n\r\n") {
+                self.send_head(out)?;
+                return Ok(i + 1);
+            }
+        }
+        if self.head.len() > MAX_HEAD {
+            out.write_all(&self.head)?;
+            self.sent += self.head.len() as u64;
+            self.head.clear();
+            self.body = Framing::UntilClose;
+        }
+        Ok(buf.len())
+    }
+
+    fn send_head(&mut self, out: &mut dyn Write) -> std::io::Result<()> {
+        let mut head = std::mem::take(&mut self.head);
+        let text = String::from_utf8_lossy(&head);
+        let mut lines = text.lines();
+        let Some(status) = lines.next().and_then(parse_status) else {
+            out.write_all(&head)?;
+            self.sent += head.len() as u64;
+            self.body = Framing::UntilClose;
+            return Ok(());
+        };
+
+        let mut length = None;
+        let mut chunked = false;
+        let mut connection = None;
+        let mut names = Vec::new();
+        for line in lines {
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            let (name, value) = (name.trim(), value.trim());
+            names.push(name.to_ascii_lowercase());
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = value.parse::<u64>().ok();
+            } else if name.eq_ignore_ascii_case("Transfer-Encoding") {
+                chunked = value
+                    .rsplit(',')
+                    .next()
+                    .is_some_and(|te| te.trim().eq_ignore_ascii_case("chunked"));
+            } else if name.eq_ignore_ascii_case("Connection") {
+                let close = value
+                    .split(',')
+                    .any(|token| token.trim().eq_ignore_ascii_case("close"));
+                connection = Some(close);
+            }
+        }
+
+        let body = match status.code() {
+            101 => Framing::Upgraded,
+            // informational, with the real response still to come
+            100..=199 => {
+                out.write_all(&head)?;
+                self.sent += head.len() as u64;
+                return Ok(());
+            }
+            204 | 304 => Framing::Done,
+            _ if chunked => Framing::Chunked(Chunks::new()),
+            _ => match length {
+                Some(0) => Framing::Done,
+                Some(left) => Framing::Length(left),
+                None => Framing::UntilClose,
+            },
+        };
+        if !matches!(body, Framing::Upgraded) {
+            // a body that runs to the close leaves no room for another
+            let delimited = self.head_only || !matches!(body, Framing::UntilClose);
+            self.keep_alive &= delimited && connection != Some(true);
+            let mut add = String::new();
+            match (self.keep_alive, self.http_1_0) {
+                _ if connection.is_some() => {}
+                (false, _) => add.push_str("Connection: close\r\n"),
+                (true, true) => add.push_str("Connection: keep-alive\r\n"),
+                (true, false) => {}
+            }
+            for (name, value) in &self.extra {
+                // the writer's own header wins, but Vary is a list and
+                // another line adds to it
+                let name_lower = name.to_ascii_lowercase();
+                if name_lower == "vary" || !names.contains(&name_lower) {
+                    add.push_str(&format!("{}: {}\r\n", name, value));
+                }
+            }
+            let at = head.len() - if head.ends_with(b"\r\n\r\n") { 2 } else { 1 };
+            head.splice(at..at, add.bytes());
+        }
+        if let (Some(server), false) = (&self.server, names.iter().any(|n| n == "server")) {
+            // right after the status line, where Response puts it
+            let at = head.iter().position(|b| *b == b'\n').unwrap_or(0) + 1;
+            head.splice(at..at, format!("Server: {}\r\n", server).into_bytes());
+        }
+
+        out.write_all(&head)?;
+        self.sent += head.len() as u64;
+        self.body = body;
+        self.status = Some(status);
+        Ok(())
+    }
+}
+
+// Follows a chunked body far enough to see where it ends.
+struct Chunks {
+    part: ChunkPart,
+    line: Vec<u8>,
+}
+
+enum ChunkPart {
+    Size,
+    Data(u64),
+    DataEnd,
+    Trailer,
+}
+
+impl Chunks {
+    fn new() -> Chunks {
+        Chunks {
+            part: ChunkPart::Size,
+            line: Vec::new(),
+        }
+    }
+
+    // How much of `buf` belongs to the body, and whether that ends it. None
+    // if the body isn't validly chunked.
+    fn advance(&mut self, buf: &[u8]) -> Option<(usize, bool)> {
+        let mut i = 0;
+        while i < buf.len() {
+            if let ChunkPart::Data(left) = &mut self.part {
+                let n = (*left).min((buf.len() - i) as u64);
+                i += n as usize;
+                *left -= n;
+                if *left == 0 {
+                    self.part = ChunkPart::DataEnd;
+                }
+                continue;
+            }
+
+            let b = buf[i];
+            i += 1;
+            if b != b'\n' {
+                if self.line.len() > MAX_HEAD {
+                    return None;
+                }
+                self.line.push(b);
+                continue;
+            }
+            let line = String::from_utf8_lossy(&self.line).trim().to_string();
+            self.line.clear();
+            self.part = match self.part {
+                ChunkPart::Size => {
+                    let size = line.split(';').next().unwrap_or("").trim();
+                    match u64::from_str_radix(size, 16) {
+                        Ok(0) => ChunkPart::Trailer,
+                        Ok(n) if !size.starts_with('+') => ChunkPart::Data(n),
+                        _ => return None,
+                    }
+                }
+                ChunkPart::DataEnd if line.is_empty() => ChunkPart::Size,
+                ChunkPart::Trailer if line.is_empty() => return Some((i, true)),
+                ChunkPart::Trailer => ChunkPart::Trailer,
+                _ => return None,
+            };
+        }
+        Some((i, false))
+    }
+}
+
+impl Drop for Exchange {
+    // Whatever of a head the last handle leaves unfinished still goes out.
+    fn drop(&mut self) {
+        if self.last_handle() {
+            let mut p = self.state.lock();
+            if matches!(p.body, Framing::Head) && !p.head.is_empty() {
+                let head = std::mem::take(&mut p.head);
+                let _ = self.inner.write_all(&head);
+            }
+        }
+    }
+}
+
+impl Read for Exchange {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut ahead = self.read_ahead.lock().unwrap_or_else(|e| e.into_inner());
+        if (ahead.position() as usize) < ahead.get_ref().len() {
+            return ahead.read(buf);
+        }
+        drop(ahead);
+        self.inner.read(buf)
+    }
+}
+
+impl Write for Exchange {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.state.lock().write(&mut *self.inner, buf)
+    }
+
+    // Response writes its heads this way, and nothing else is expected to,
+    // so this is how a second response is told from body bytes that only
+    // look like a head.
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> std::io::Result<usize> {
+        let buf = bufs
+            .iter()
+            .find(|b| !b.is_empty())
+            .map_or(&[][..], |b| &**b);
+        self.state.lock().write_response_head(&mut *self.inner, buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Stream for Exchange {
+    fn try_clone(&self) -> std::io::Result<Box<dyn Stream>> {
+        Ok(Box::new(self.duplicate()?))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    fn shutdown(&self) -> std::io::Result<()> {
+        self.inner.shutdown()
+    }
+
+    fn add_header(&mut self, name: &str, value: &str) {
+        let mut p = self.state.lock();
+        p.extra.push((name.to_string(), value.to_string()));
+    }
+
+    // Hands a file body to the connection's own write_file when it fits in
+    // what the head announced, so sendfile(2) still gets used. For HEAD the
+    // file isn't read at all.
+    fn write_file(&mut self, file: &mut std::fs::File, len: u64) -> err::Result<u64> {
+        let mut p = self.state.lock();
+        if let Framing::Length(left) = p.body {
+            if len <= left {
+                let n = match p.head_only {
+                    true => len,
+                    false => self.inner.write_file(file, len)?,
+                };
+                if !p.head_only {
+                    p.sent += n;
+                }
+                p.body = match left - n {
+                    0 => Framing::Done,
+                    left => Framing::Length(left),
+                };
+                return Ok(n);
+            }
+        }
+        drop(p);
+        copy_body(Read::take(file, len), self)
+    }
+}
@@ -2,10 +2,28 @@ use base64::engine::general_purpose::STANDARD as b64;
 use base64::Engine;
 use sha1::{Digest, Sha1};
 use std::collections::HashMap;
-use std::io::{BufRead, BufReader, Write};
-use std::net::{TcpListener, TcpStream};
-
+use std::io::{BufRead, BufReader, IoSlice, Read, Seek, SeekFrom, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub mod cors;
+pub mod date;
+mod deflate;
 pub mod err;
+mod exchange;
+mod mime;
+pub mod multipart;
+mod pool;
+pub mod router;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
 
 #[cfg(test)]
 mod tests;
@@ -14,16 +32,190 @@ pub struct Req {
     pub version: String,
     pub verb: Verb,
     pub path: String,
+    pub query: HashMap<String, String>,
+    pub params: HashMap<String, String>,
     pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+    pub remote_addr: Option<SocketAddr>,
+    header_lines: Vec<(String, String)>,
+    body_source: Option<Box<dyn Read + Send>>,
+    limits: Limits,
+    // the connection's read buffer size, for a websocket upgraded from it
+    read_buffer: usize,
+}
+
+pub struct BodyReader {
+    src: BufReader<Box<dyn Read + Send>>,
+    chunked: bool,
+    // bytes left in the body, or in the current chunk
+    remaining: u64,
+    done: bool,
+    limits: Limits,
+    total: u64,
+}
+
+impl BodyReader {
+    fn next_chunk(&mut self) -> err::Result<()> {
+        let max_line_len = self.limits.max_line_len;
+        let mut buf = String::new();
+        read_line_limited(&mut self.src, &mut buf, max_line_len)?;
+        let size = buf.split(';').next().unwrap_or("").trim();
+        self.remaining = match u64::from_str_radix(size, 16) {
+            Ok(n) if !size.starts_with('+') => n,
+            _ => return err::input(format!("bad chunk size: {}", size)),
+        };
+        if self.remaining > 0 {
+            return Ok(());
+        }
+
+        // skip trailer headers
+        self.done = true;
+        loop {
+            buf.clear();
+            if read_line_limited(&mut self.src, &mut buf, max_line_len)? == 0
+                || buf.trim().is_empty()
+            {
+                return Ok(());
+            }
+        }
+    }
+
+    fn end_chunk(&mut self) -> err::Result<()> {
+        let mut crlf = [0u8; 2];
+        self.src.read_exact(&mut crlf)?;
+        if &crlf != b"\r\n" {
+            return err::input("chunk not terminated by CRLF".to_string());
+        }
+        Ok(())
+    }
+}
+
+fn to_io(e: err::Error) -> std::io::Error {
+    match e {
+        err::Error::Io(e) => e,
+        e => std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()),
+    }
+}
+
+impl Read for BodyReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.remaining == 0 && !self.done {
+            self.next_chunk().map_err(to_io)?;
+        }
+        if self.remaining == 0 || buf.is_empty() {
+            return Ok(0);
+        }
+        let max_body_size = self.limits.max_body_size as u64;
+        if self.remaining > max_body_size - self.total {
+            let msg = format!("body longer than {} bytes", max_body_size);
+            return Err(to_io(err::Error::TooLarge(msg)));
+        }
+
+        let max = self.remaining.min(buf.len() as u64) as usize;
+        let n = self.src.read(&mut buf[..max])?;
+        if n == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining -= n as u64;
+        self.total += n as u64;
+        if self.remaining == 0 && self.chunked {
+            self.end_chunk().map_err(to_io)?;
+        }
+        Ok(n)
+    }
+}
+
+#[derive(Clone)]
+pub struct Limits {
+    pub max_line_len: usize,
+    pub max_headers: usize,
+    pub max_header_bytes: usize,
+    pub max_body_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_line_len: 8 * 1024,
+            max_headers: 100,
+            max_header_bytes: 64 * 1024,
+            max_body_size: 4 * 1024 * 1024,
+        }
+    }
+}
+
+fn read_line_limited<T: BufRead>(client: T, buf: &mut String, max: usize) -> err::Result<usize> {
+    let n = client.take(max as u64 + 1).read_line(buf)?;
+    if n > max {
+        return err::input(format!("line longer than {} bytes", max));
+    }
+    // the peer hung up part way through a line
+    if n > 0 && !buf.ends_with('\n') {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(n)
+}
+
+fn read_chunked<T: BufRead>(mut client: T, limits: &Limits) -> err::Result<Vec<u8>> {
+    let mut body = Vec::new();
+    let mut buf = String::new();
+    loop {
+        buf.clear();
+        read_line_limited(&mut client, &mut buf, limits.max_line_len)?;
+        let size = buf.split(';').next().unwrap_or("").trim();
+        let len = match usize::from_str_radix(size, 16) {
+            Ok(n) if !size.starts_with('+') => n,
+            _ => return err::input(format!("bad chunk size: {}", size)),
+        };
+        if len == 0 {
+            break;
+        }
+
+        let start = body.len();
+        if len > limits.max_body_size - start {
+            return err::too_large(format!("body longer than {} bytes", limits.max_body_size));
+        }
+        body.resize(start + len, 0);
+        client.read_exact(&mut body[start..])?;
+        let mut crlf = [0u8; 2];
+        client.read_exact(&mut crlf)?;
+        if &crlf != b"\r\n" {
+            return err::input("chunk not terminated by CRLF".to_string());
+        }
+    }
+
+    // skip trailer headers
+    loop {
+        buf.clear();
+        if read_line_limited(&mut client, &mut buf, limits.max_line_len)? == 0
+            || buf.trim().is_empty()
+        {
+            return Ok(body);
+        }
+    }
 }
 
 impl Req {
-    pub fn parse<T: BufRead>(mut client: T) -> err::Result<Req> {
+    pub fn parse<T: BufRead>(client: T) -> err::Result<Req> {
+        Req::parse_with_limits(client, &Limits::default())
+    }
+
+    pub fn parse_with_limits<T: BufRead>(mut client: T, limits: &Limits) -> err::Result<Req> {
+        let mut req = Req::parse_head(&mut client, limits)?;
+        req.read_body(client, limits)?;
+        Ok(req)
+    }
+
+    fn parse_head<T: BufRead>(mut client: T, limits: &Limits) -> err::Result<Req> {
         let mut buf = String::new();
 
-        client.read_line(&mut buf)?;
+        read_line_limited(&mut client, &mut buf, limits.max_line_len)?;
 
-        let mut req = buf.trim().split(' ');
+        let line = buf.trim();
+        if line.split(' ').count() > 3 {
+            return err::input(format!("malformed request line: {}", line));
+        }
+        let mut req = line.split(' ');
         let verb = match req.next() {
             Some(v) => v,
             None => return err::input("no verb".to_string()),
@@ -32,57 +224,420 @@ impl Req {
             Some(v) => v,
             None => return err::input(format!("unknown verb: {}", verb)),
         };
-        let path = match req.next() {
-            Some(v) => v.to_string(),
+        let target = match req.next() {
+            Some(v) => v,
             None => return err::input("no path".to_string()),
         };
+        let (path, query) = match target.split_once('?') {
+            Some((p, q)) => (percent_decode(p, false)?, parse_query(q)?),
+            None => (percent_decode(target, false)?, HashMap::new()),
+        };
         let version = match req.next() {
-            Some(v) => v.to_string(),
+            Some(v @ ("HTTP/1.0" | "HTTP/1.1")) => v.to_string(),
+            Some(v) => return err::input(format!("unsupported version: {}", v)),
             None => return err::input("no version".to_string()),
         };
 
-        while let Some(s) = req.next() {
-            println!("unexpected bit: {}", s);
-        }
-
         let mut headers = HashMap::new();
+        let mut header_lines = Vec::new();
+        let mut header_bytes = 0;
         let mut buf = String::new();
         loop {
             buf.clear();
-            client.read_line(&mut buf)?;
+            let n = read_line_limited(&mut client, &mut buf, limits.max_line_len)?;
+            if n == 0 {
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+            }
+            header_bytes += n;
+            if header_bytes > limits.max_header_bytes {
+                return err::input(format!(
+                    "headers longer than {} bytes",
+                    limits.max_header_bytes
+                ));
+            }
             let hdr = buf.trim();
             if hdr.is_empty() {
                 break;
             }
-            let mut hdr = hdr.split(':');
-            let name = match hdr.next() {
-                Some(s) => s,
-                None => continue,
-            };
-            let value = match hdr.next() {
-                Some(s) => s,
+            let (name, value) = match hdr.split_once(':') {
+                Some(kv) => kv,
                 None => continue,
             };
-            headers.insert(name.trim().to_string(), value.trim().to_string());
+            let (name, value) = (name.trim(), value.trim());
+            headers
+                .entry(name.to_ascii_lowercase())
+                .or_insert_with(|| value.to_string());
+            header_lines.push((name.to_string(), value.to_string()));
+            if header_lines.len() > limits.max_headers {
+                return err::input(format!("more than {} headers", limits.max_headers));
+            }
         }
 
-        Ok(Req {
+        // HTTP/1.0 clients may leave Host out, HTTP/1.1 ones must send it
+        if version == "HTTP/1.1" && headers.get("host").is_none_or(|h| h.is_empty()) {
+            return err::input("missing host header".to_string());
+        }
+
+        let req = Req {
             version,
-            verb: verb,
-            path: path,
+            verb,
+            path,
+            query,
+            params: HashMap::new(),
             headers,
-        })
+            body: Vec::new(),
+            remote_addr: None,
+            header_lines,
+            body_source: None,
+            limits: limits.clone(),
+            read_buffer: DEFAULT_READ_BUFFER,
+        };
+        req.check_framing()?;
+        Ok(req)
+    }
+
+    // Refuses bodies whose length could be read more than one way, as
+    // something in front of us might read it the other way and slip a
+    // request past (RFC 9112 section 6).
+    fn check_framing(&self) -> err::Result<()> {
+        if !self.header_all("Transfer-Encoding").is_empty() {
+            if !self.chunked() {
+                return err::input("transfer coding is not chunked".to_string());
+            }
+            if !self.header_all("Content-Length").is_empty() {
+                return err::input("both transfer encoding and content length".to_string());
+            }
+        }
+        self.content_length().map(|_| ())
+    }
+
+    fn read_body<T: BufRead>(&mut self, mut client: T, limits: &Limits) -> err::Result<()> {
+        self.body = if self.chunked() {
+            read_chunked(&mut client, limits)?
+        } else {
+            let mut body = vec![0u8; self.buffered_length(limits)?];
+            client.read_exact(&mut body)?;
+            body
+        };
+        Ok(())
+    }
+
+    fn chunked(&self) -> bool {
+        self.header_all("Transfer-Encoding")
+            .iter()
+            .flat_map(|te| te.split(','))
+            .last()
+            .is_some_and(|te| te.trim().eq_ignore_ascii_case("chunked"))
+    }
+
+    fn content_length(&self) -> err::Result<usize> {
+        let mut len = None;
+        for value in self
+            .header_all("Content-Length")
+            .iter()
+            .flat_map(|v| v.split(','))
+        {
+            let value = value.trim();
+            let n = match !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()) {
+                true => value.parse::<usize>().ok(),
+                false => None,
+            };
+            let Some(n) = n else {
+                return err::input(format!("bad content length: {}", value));
+            };
+            if len.is_some_and(|len| len != n) {
+                return err::input("conflicting content lengths".to_string());
+            }
+            len = Some(n);
+        }
+        Ok(len.unwrap_or(0))
+    }
+
+    fn buffered_length(&self, limits: &Limits) -> err::Result<usize> {
+        let len = self.content_length()?;
+        if len > limits.max_body_size {
+            return err::too_large(format!("body longer than {} bytes", limits.max_body_size));
+        }
+        Ok(len)
+    }
+
+    // Whether the body is big enough, or of unknown length, to be left on
+    // the connection for `body_reader` rather than read into `body`.
+    fn streams_body(&self, stream_above: Option<usize>) -> err::Result<bool> {
+        match stream_above {
+            Some(n) => Ok(self.chunked() || self.content_length()? > n),
+            None => Ok(false),
+        }
+    }
+
+    // Reads the body as it arrives: straight off the connection when the
+    // server left it there (see Config::stream_body_above), otherwise from
+    // the buffered `body`.
+    pub fn body_reader(mut self) -> BodyReader {
+        let chunked = self.body_source.is_some() && self.chunked();
+        let remaining = match &self.body_source {
+            Some(_) if chunked => 0,
+            Some(_) => self.content_length().unwrap_or(0) as u64,
+            None => self.body.len() as u64,
+        };
+        let src: Box<dyn Read + Send> = match self.body_source.take() {
+            Some(src) => src,
+            None => Box::new(std::io::Cursor::new(self.body)),
+        };
+        BodyReader {
+            src: BufReader::new(src),
+            chunked,
+            remaining,
+            done: !chunked,
+            limits: self.limits,
+            total: 0,
+        }
+    }
+
+    pub fn expects_continue(&self) -> bool {
+        self.version == "HTTP/1.1"
+            && self
+                .header("Expect")
+                .is_some_and(|e| e.eq_ignore_ascii_case("100-continue"))
+    }
+
+    // The request without its body, for pages such as on_not_found that are
+    // sent after the handler has taken the request itself.
+    fn head_copy(&self) -> Req {
+        Req {
+            version: self.version.clone(),
+            verb: self.verb,
+            path: self.path.clone(),
+            query: self.query.clone(),
+            params: self.params.clone(),
+            headers: self.headers.clone(),
+            body: Vec::new(),
+            remote_addr: self.remote_addr,
+            header_lines: self.header_lines.clone(),
+            body_source: None,
+            limits: self.limits.clone(),
+            read_buffer: self.read_buffer,
+        }
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .get(&name.to_ascii_lowercase())
+            .map(|s| s.as_str())
+    }
+
+    pub fn keep_alive(&self) -> bool {
+        let mut keep_alive = self.version == "HTTP/1.1";
+        for token in self.header("Connection").unwrap_or("").split(',') {
+            let token = token.trim();
+            if token.eq_ignore_ascii_case("keep-alive") {
+                keep_alive = true;
+            } else if token.eq_ignore_ascii_case("close") || token.eq_ignore_ascii_case("upgrade") {
+                return false;
+            }
+        }
+        keep_alive
+    }
+
+    pub fn header_all(&self, name: &str) -> Vec<&str> {
+        self.header_lines
+            .iter()
+            .filter(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+            .collect()
+    }
+
+    pub fn host(&self) -> Option<&str> {
+        self.header("Host")
+    }
+
+    pub fn raw_headers(&self) -> &[(String, String)] {
+        &self.header_lines
+    }
+
+    pub fn path_segments(&self) -> Vec<&str> {
+        self.path.split('/').filter(|s| !s.is_empty()).collect()
+    }
+
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        self.query.get(name).map(|s| s.as_str())
+    }
+
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(|s| s.as_str())
+    }
+
+    pub fn form(&self) -> err::Result<HashMap<String, String>> {
+        let content_type = self.header("Content-Type").unwrap_or("");
+        let mime = content_type.split(';').next().unwrap_or("").trim();
+        if !mime.eq_ignore_ascii_case("application/x-www-form-urlencoded") {
+            return Ok(HashMap::new());
+        }
+        match std::str::from_utf8(&self.body) {
+            Ok(body) => parse_query(body.trim_end()),
+            Err(_) => err::input("form body is not valid UTF-8".to_string()),
+        }
+    }
+
+    pub fn multipart(&self) -> err::Result<Vec<multipart::Part>> {
+        multipart::parse(self)
+    }
+
+    pub fn content_type(&self) -> Option<MediaType> {
+        MediaType::parse(self.header("Content-Type")?)
+    }
+
+    pub fn basic_auth(&self) -> Option<(String, String)> {
+        let (scheme, creds) = self.header("Authorization")?.trim().split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return None;
+        }
+        let creds = String::from_utf8(b64.decode(creds.trim()).ok()?).ok()?;
+        let (user, pass) = creds.split_once(':')?;
+        Some((user.to_string(), pass.to_string()))
+    }
+
+    pub fn bearer_token(&self) -> Option<&str> {
+        let (scheme, token) = self.header("Authorization")?.trim().split_once(' ')?;
+        let token = token.trim();
+        match scheme.eq_ignore_ascii_case("bearer") && !token.is_empty() {
+            true => Some(token),
+            false => None,
+        }
+    }
+
+    pub fn client_ip(&self, trust_proxy: bool) -> Option<IpAddr> {
+        let peer = self.remote_addr.map(|a| a.ip());
+        if !trust_proxy {
+            return peer;
+        }
+
+        let forwarded_for = self
+            .header("X-Forwarded-For")
+            .and_then(|v| v.split(',').next())
+            .and_then(parse_ip);
+        let forwarded = || {
+            self.header("Forwarded")
+                .and_then(|v| v.split(',').next())
+                .and_then(|v| {
+                    v.split(';')
+                        .filter_map(|p| p.trim().split_once('='))
+                        .find(|(k, _)| k.eq_ignore_ascii_case("for"))
+                })
+                .and_then(|(_, v)| parse_ip(v))
+        };
+        forwarded_for.or_else(forwarded).or(peer)
+    }
+
+    pub fn cookies(&self) -> HashMap<String, String> {
+        let mut cookies = HashMap::new();
+        for line in self.header_all("Cookie") {
+            for pair in line.split(';') {
+                let (name, value) = match pair.split_once('=') {
+                    Some((n, v)) => (n.trim(), v.trim()),
+                    None => continue,
+                };
+                if name.is_empty() {
+                    continue;
+                }
+                let value = match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+                    Some(v) => v,
+                    None => value,
+                };
+                cookies
+                    .entry(name.to_string())
+                    .or_insert_with(|| value.to_string());
+            }
+        }
+        cookies
+    }
+
+    pub fn cookie(&self, name: &str) -> Option<String> {
+        self.cookies().remove(name)
+    }
+}
+
+fn parse_ip(s: &str) -> Option<IpAddr> {
+    let s = s.trim().trim_matches('"');
+    if let Ok(ip) = s.parse() {
+        return Some(ip);
+    }
+    if let Ok(addr) = s.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+    s.strip_prefix('[')?.strip_suffix(']')?.parse().ok()
+}
+
+fn parse_query(s: &str) -> err::Result<HashMap<String, String>> {
+    let mut query = HashMap::new();
+    for pair in s.split('&') {
+        if pair.is_empty() {
+            continue;
+        }
+        let (name, value) = match pair.split_once('=') {
+            Some((n, v)) => (n, v),
+            None => (pair, ""),
+        };
+        query.insert(percent_decode(name, true)?, percent_decode(value, true)?);
+    }
+    Ok(query)
+}
+
+fn percent_decode(s: &str, plus_as_space: bool) -> err::Result<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut it = s.bytes();
+    while let Some(b) = it.next() {
+        match b {
+            b'%' => {
+                let hi = it.next().and_then(hex_value);
+                let lo = it.next().and_then(hex_value);
+                match (hi, lo) {
+                    (Some(hi), Some(lo)) => bytes.push((hi << 4) | lo),
+                    _ => return err::input(format!("bad percent escape in: {}", s)),
+                }
+            }
+            b'+' if plus_as_space => bytes.push(b' '),
+            _ => bytes.push(b),
+        }
+    }
+    match String::from_utf8(bytes) {
+        Ok(s) => Ok(s),
+        Err(_) => err::input(format!("bad utf-8 in: {}", s)),
     }
 }
 
+fn hex_value(b: u8) -> Option<u8> {
+    match b {
+        b'0'..=b'9' => Some(b - b'0'),
+        b'a'..=b'f' => Some(b - b'a' + 10),
+        b'A'..=b'F' => Some(b - b'A' + 10),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Verb {
     Get,
+    Post,
+    Put,
+    Delete,
+    Patch,
+    Head,
+    Options,
+    Trace,
 }
 
 impl Verb {
     pub fn parse(s: &str) -> Option<Verb> {
         match s {
             "GET" => Some(Verb::Get),
+            "POST" => Some(Verb::Post),
+            "PUT" => Some(Verb::Put),
+            "DELETE" => Some(Verb::Delete),
+            "PATCH" => Some(Verb::Patch),
+            "HEAD" => Some(Verb::Head),
+            "OPTIONS" => Some(Verb::Options),
+            "TRACE" => Some(Verb::Trace),
             _ => None,
         }
     }
@@ -90,6 +645,13 @@ impl Verb {
     pub fn to_string(&self) -> &'static str {
         match self {
             Verb::Get => "GET",
+            Verb::Post => "POST",
+            Verb::Put => "PUT",
+            Verb::Delete => "DELETE",
+            Verb::Patch => "PATCH",
+            Verb::Head => "HEAD",
+            Verb::Options => "OPTIONS",
+            Verb::Trace => "TRACE",
         }
     }
 }
@@ -100,117 +662,1633 @@ impl std::fmt::Display for Verb {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Status {
+    Continue,
     SwitchingProtocols,
     OK,
+    Created,
+    Accepted,
+    NoContent,
+    PartialContent,
+    MovedPermanently,
+    Found,
+    SeeOther,
+    NotModified,
+    TemporaryRedirect,
+    PermanentRedirect,
     BadRequest,
+    Unauthorized,
+    Forbidden,
     NotFound,
     MethodNotAllowed,
+    Conflict,
+    PayloadTooLarge,
+    RangeNotSatisfiable,
+    TooManyRequests,
+    InternalServerError,
+    NotImplemented,
+    ServiceUnavailable,
+    Custom(u16, &'static str),
 }
 
+const STATUSES: &[Status] = &[
+    Status::Continue,
+    Status::SwitchingProtocols,
+    Status::OK,
+    Status::Created,
+    Status::Accepted,
+    Status::NoContent,
+    Status::PartialContent,
+    Status::MovedPermanently,
+    Status::Found,
+    Status::SeeOther,
+    Status::NotModified,
+    Status::TemporaryRedirect,
+    Status::PermanentRedirect,
+    Status::BadRequest,
+    Status::Unauthorized,
+    Status::Forbidden,
+    Status::NotFound,
+    Status::MethodNotAllowed,
+    Status::Conflict,
+    Status::PayloadTooLarge,
+    Status::RangeNotSatisfiable,
+    Status::TooManyRequests,
+    Status::InternalServerError,
+    Status::NotImplemented,
+    Status::ServiceUnavailable,
+];
+
 impl Status {
-    pub fn to_string(&self) -> &'static str {
+    pub fn from_code(code: u16) -> Status {
+        match STATUSES.iter().find(|s| s.code() == code) {
+            Some(s) => *s,
+            None => {
+                let reason = match code / 100 {
+                    1 => "Informational",
+                    2 => "Success",
+                    3 => "Redirection",
+                    4 => "Client Error",
+                    5 => "Server Error",
+                    _ => "",
+                };
+                Status::Custom(code, reason)
+            }
+        }
+    }
+
+    pub fn code(&self) -> u16 {
+        match self {
+            Status::Continue => 100,
+            Status::SwitchingProtocols => 101,
+            Status::OK => 200,
+            Status::Created => 201,
+            Status::Accepted => 202,
+            Status::NoContent => 204,
+            Status::PartialContent => 206,
+            Status::MovedPermanently => 301,
+            Status::Found => 302,
+            Status::SeeOther => 303,
+            Status::NotModified => 304,
+            Status::TemporaryRedirect => 307,
+            Status::PermanentRedirect => 308,
+            Status::BadRequest => 400,
+            Status::Unauthorized => 401,
+            Status::Forbidden => 403,
+            Status::NotFound => 404,
+            Status::MethodNotAllowed => 405,
+            Status::Conflict => 409,
+            Status::PayloadTooLarge => 413,
+            Status::RangeNotSatisfiable => 416,
+            Status::TooManyRequests => 429,
+            Status::InternalServerError => 500,
+            Status::NotImplemented => 501,
+            Status::ServiceUnavailable => 503,
+            Status::Custom(code, _) => *code,
+        }
+    }
+
+    pub fn reason(&self) -> &'static str {
         match self {
-            Status::SwitchingProtocols => "101 Switching Protocols",
-            Status::OK => "200 OK",
-            Status::BadRequest => "400 Bad Request",
-            Status::NotFound => "404 Not Found",
-            Status::MethodNotAllowed => "405 Method Not Allowed",
+            Status::Continue => "Continue",
+            Status::SwitchingProtocols => "Switching Protocols",
+            Status::OK => "OK",
+            Status::Created => "Created",
+            Status::Accepted => "Accepted",
+            Status::NoContent => "No Content",
+            Status::PartialContent => "Partial Content",
+            Status::MovedPermanently => "Moved Permanently",
+            Status::Found => "Found",
+            Status::SeeOther => "See Other",
+            Status::NotModified => "Not Modified",
+            Status::TemporaryRedirect => "Temporary Redirect",
+            Status::PermanentRedirect => "Permanent Redirect",
+            Status::BadRequest => "Bad Request",
+            Status::Unauthorized => "Unauthorized",
+            Status::Forbidden => "Forbidden",
+            Status::NotFound => "Not Found",
+            Status::MethodNotAllowed => "Method Not Allowed",
+            Status::Conflict => "Conflict",
+            Status::PayloadTooLarge => "Payload Too Large",
+            Status::RangeNotSatisfiable => "Range Not Satisfiable",
+            Status::TooManyRequests => "Too Many Requests",
+            Status::InternalServerError => "Internal Server Error",
+            Status::NotImplemented => "Not Implemented",
+            Status::ServiceUnavailable => "Service Unavailable",
+            Status::Custom(_, reason) => reason,
         }
     }
 }
 
 impl std::fmt::Display for Status {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.to_string())
+        write!(f, "{} {}", self.code(), self.reason())
     }
 }
 
-pub fn send_headers(
-    client: &mut TcpStream,
-    status: Status,
-    content_type: &str,
-    len: u64,
-) -> err::Result<()> {
-    println!(" => {}", status);
-    write!(client, "HTTP/1.0 {}\n", status)?;
-    write!(client, "Server: webd 0.1\n")?;
-    write!(client, "Content-Type: {}\n", content_type)?;
-    write!(client, "Content-Length: {}\n", len)?;
-    write!(client, "\n")?;
+enum Body {
+    Empty,
+    Bytes(Vec<u8>),
+    File(PathBuf),
+}
 
-    Ok(())
+enum Range {
+    Full,
+    Partial(u64, u64),
+    Unsatisfiable,
 }
 
-pub fn send_str(
-    mut client: TcpStream,
-    status: Status,
-    content_type: &str,
-    content: &str,
-) -> err::Result<()> {
-    send_headers(&mut client, status, content_type, content.len() as u64)?;
+fn parse_range(range: &str, len: u64) -> Range {
+    let spec = match range.trim().strip_prefix("bytes=") {
+        Some(s) if !s.contains(',') => s.trim(),
+        _ => return Range::Full,
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some(se) => se,
+        None => return Range::Full,
+    };
+    let (start, end) = match (start.parse::<u64>(), end.parse::<u64>()) {
+        (Ok(s), Ok(e)) if s <= e => (s, e.min(len.saturating_sub(1))),
+        (Ok(s), Err(_)) if end.is_empty() => (s, len.saturating_sub(1)),
+        (Err(_), Ok(n)) if start.is_empty() && n > 0 => {
+            (len.saturating_sub(n), len.saturating_sub(1))
+        }
+        (Err(_), Ok(_)) if start.is_empty() => return Range::Unsatisfiable,
+        _ => return Range::Full,
+    };
+    if start >= len {
+        return Range::Unsatisfiable;
+    }
+    Range::Partial(start, end)
+}
 
-    write!(client, "{}", content)?;
+// A parsed Content-Type such as `text/html; charset=utf-8`. The essence and
+// parameter names are lowercased; parameter values are kept as sent.
+#[derive(Debug, PartialEq)]
+pub struct MediaType {
+    pub essence: String,
+    pub params: HashMap<String, String>,
+}
 
-    Ok(())
+impl MediaType {
+    pub fn parse(value: &str) -> Option<MediaType> {
+        let essence = value.split(';').next()?.trim();
+        let (kind, subtype) = essence.split_once('/')?;
+        if kind.trim().is_empty() || subtype.trim().is_empty() {
+            return None;
+        }
+        Some(MediaType {
+            essence: essence.to_ascii_lowercase(),
+            params: multipart::params(value).into_iter().collect(),
+        })
+    }
+
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .get(&name.to_ascii_lowercase())
+            .map(|v| v.as_str())
+    }
 }
 
-pub fn send_file(
-    mut client: TcpStream,
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+pub struct Cookie {
+    name: String,
+    value: String,
+    path: Option<String>,
+    domain: Option<String>,
+    max_age: Option<u64>,
+    expires: Option<SystemTime>,
+    http_only: bool,
+    secure: bool,
+    same_site: Option<SameSite>,
+}
+
+// Anything that would end the attribute, or the header, early.
+fn check_cookie_part(what: &str, s: &str) -> err::Result<String> {
+    if s.bytes()
+        .any(|b| b.is_ascii_control() || b.is_ascii_whitespace() || b == b';')
+    {
+        return err::input(format!("bad character in cookie {}: {:?}", what, s));
+    }
+    Ok(s.to_string())
+}
+
+impl Cookie {
+    pub fn new(name: &str, value: &str) -> err::Result<Cookie> {
+        if name.is_empty() || name.contains('=') {
+            return err::input(format!("bad cookie name: {:?}", name));
+        }
+        Ok(Cookie {
+            name: check_cookie_part("name", name)?,
+            value: check_cookie_part("value", value)?,
+            path: None,
+            domain: None,
+            max_age: None,
+            expires: None,
+            http_only: false,
+            secure: false,
+            same_site: None,
+        })
+    }
+
+    pub fn path(mut self, path: &str) -> err::Result<Cookie> {
+        self.path = Some(check_cookie_part("path", path)?);
+        Ok(self)
+    }
+
+    pub fn domain(mut self, domain: &str) -> err::Result<Cookie> {
+        self.domain = Some(check_cookie_part("domain", domain)?);
+        Ok(self)
+    }
+
+    pub fn max_age(mut self, max_age: Duration) -> Cookie {
+        self.max_age = Some(max_age.as_secs());
+        self
+    }
+
+    pub fn expires(mut self, expires: SystemTime) -> Cookie {
+        self.expires = Some(expires);
+        self
+    }
+
+    pub fn http_only(mut self) -> Cookie {
+        self.http_only = true;
+        self
+    }
+
+    pub fn secure(mut self) -> Cookie {
+        self.secure = true;
+        self
+    }
+
+    pub fn same_site(mut self, same_site: SameSite) -> Cookie {
+        self.same_site = Some(same_site);
+        self
+    }
+}
+
+impl std::fmt::Display for Cookie {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.name, self.value)?;
+        if let Some(path) = &self.path {
+            write!(f, "; Path={}", path)?;
+        }
+        if let Some(domain) = &self.domain {
+            write!(f, "; Domain={}", domain)?;
+        }
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age)?;
+        }
+        if let Some(expires) = self.expires {
+            write!(f, "; Expires={}", date::format(expires))?;
+        }
+        if self.http_only {
+            write!(f, "; HttpOnly")?;
+        }
+        if self.secure {
+            write!(f, "; Secure")?;
+        }
+        match self.same_site {
+            Some(SameSite::Strict) => write!(f, "; SameSite=Strict"),
+            Some(SameSite::Lax) => write!(f, "; SameSite=Lax"),
+            Some(SameSite::None) => write!(f, "; SameSite=None"),
+            None => Ok(()),
+        }
+    }
+}
+
+fn accepts_gzip(coding: &str) -> bool {
+    let mut parts = coding.split(';');
+    if !parts
+        .next()
+        .unwrap_or("")
+        .trim()
+        .eq_ignore_ascii_case("gzip")
+    {
+        return false;
+    }
+    parts
+        .filter_map(|p| p.trim().strip_prefix("q="))
+        .all(|q| q.parse::<f32>().is_ok_and(|q| q > 0.0))
+}
+
+pub struct Response {
+    version: &'static str,
     status: Status,
-    content_type: &str,
-    fname: &str,
-) -> err::Result<()> {
-    let len = std::fs::metadata(fname)?.len();
-    send_headers(&mut client, status, content_type, len)?;
+    headers: Vec<(String, String)>,
+    body: Body,
+    range: Option<String>,
+    if_none_match: Option<String>,
+    if_modified_since: Option<SystemTime>,
+    accept_gzip: Option<bool>,
+    server: Option<String>,
+}
+
+impl Response {
+    pub fn new(status: Status) -> Response {
+        Response {
+            version: "HTTP/1.1",
+            status,
+            headers: Vec::new(),
+            body: Body::Empty,
+            range: None,
+            if_none_match: None,
+            if_modified_since: None,
+            accept_gzip: None,
+            server: None,
+        }
+    }
 
-    let mut file = std::fs::File::open(fname)?;
-    std::io::copy(&mut file, &mut client)?;
+    pub fn parse<T: BufRead>(client: T) -> err::Result<Response> {
+        Response::parse_with_limits(client, &Limits::default())
+    }
+
+    pub fn parse_with_limits<T: BufRead>(mut client: T, limits: &Limits) -> err::Result<Response> {
+        let mut buf = String::new();
+        read_line_limited(&mut client, &mut buf, limits.max_line_len)?;
+        let mut line = buf.trim().splitn(3, ' ');
+        let version = match line.next() {
+            Some("HTTP/1.0") => "HTTP/1.0",
+            Some("HTTP/1.1") => "HTTP/1.1",
+            Some(v) => return err::input(format!("unsupported version: {}", v)),
+            None => return err::input("no version".to_string()),
+        };
+        let status = match line.next().map(|c| c.parse::<u16>()) {
+            Some(Ok(code)) => Status::from_code(code),
+            _ => return err::input(format!("bad status line: {}", buf.trim())),
+        };
+
+        let mut resp = Response::new(status);
+        resp.version = version;
+        loop {
+            buf.clear();
+            read_line_limited(&mut client, &mut buf, limits.max_line_len)?;
+            let hdr = buf.trim();
+            if hdr.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = hdr.split_once(':') {
+                resp = resp.header(name.trim(), value.trim());
+            }
+        }
+
+        let chunked = resp
+            .header_value("Transfer-Encoding")
+            .is_some_and(|te| te.trim().eq_ignore_ascii_case("chunked"));
+        let body = if chunked {
+            read_chunked(&mut client, limits)?
+        } else if let Some(len) = resp.header_value("Content-Length") {
+            let len = match len.parse::<usize>() {
+                Ok(n) => n,
+                Err(_) => return err::input(format!("bad content length: {}", len)),
+            };
+            if len > limits.max_body_size {
+                return err::too_large(format!("body longer than {} bytes", limits.max_body_size));
+            }
+            let mut body = vec![0u8; len];
+            client.read_exact(&mut body)?;
+            body
+        } else if matches!(status.code(), 100..=199 | 204 | 304) {
+            Vec::new()
+        } else {
+            let mut body = Vec::new();
+            let max = limits.max_body_size as u64;
+            if client.take(max + 1).read_to_end(&mut body)? as u64 > max {
+                return err::too_large(format!("body longer than {} bytes", limits.max_body_size));
+            }
+            body
+        };
+        if !body.is_empty() {
+            resp.body = Body::Bytes(body);
+        }
+        Ok(resp)
+    }
+
+    pub fn version(&self) -> &str {
+        self.version
+    }
+
+    pub fn status(&self) -> Status {
+        self.status
+    }
+
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
+    pub fn header_value(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn body(&self) -> &[u8] {
+        match &self.body {
+            Body::Bytes(bytes) => bytes,
+            _ => &[],
+        }
+    }
+
+    pub fn server_name(mut self, name: Option<&str>) -> Response {
+        self.server = name.map(|n| n.to_string());
+        self
+    }
+
+    pub fn for_request(mut self, req: &Req) -> Response {
+        self.range = req.header("Range").map(|s| s.to_string());
+        self.if_none_match = req.header("If-None-Match").map(|s| s.to_string());
+        self.if_modified_since = req.header("If-Modified-Since").and_then(date::parse);
+        self.accept_gzip = Some(
+            req.header("Accept-Encoding")
+                .is_some_and(|v| v.split(',').any(accepts_gzip)),
+        );
+        self
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn cookie(self, cookie: &Cookie) -> Response {
+        self.header("Set-Cookie", &cookie.to_string())
+    }
+
+    pub fn content_type(self, content_type: &str) -> Response {
+        self.header("Content-Type", content_type)
+    }
+
+    pub fn body_str(self, body: &str) -> Response {
+        self.body_bytes(body.as_bytes().to_vec())
+    }
+
+    pub fn body_bytes(mut self, body: Vec<u8>) -> Response {
+        self.body = Body::Bytes(body);
+        self
+    }
+
+    pub fn body_file(mut self, fname: impl AsRef<Path>) -> Response {
+        self.body = Body::File(fname.as_ref().to_path_buf());
+        self
+    }
+
+    fn write_head(&self, out: &mut impl Write, len: Option<u64>) -> err::Result<()> {
+        let mut head = Vec::new();
+        write!(head, "{} {}\r\n", self.version, self.status)?;
+        if let Some(server) = &self.server {
+            write!(head, "Server: {}\r\n", server)?;
+        }
+        write!(head, "Date: {}\r\n", date::now())?;
+        for (name, value) in &self.headers {
+            write!(head, "{}: {}\r\n", name, value)?;
+        }
+        match len {
+            // these never have a body to give the length of (RFC 9110 8.6),
+            // and on a 304 it would be taken for the cached one's length
+            _ if matches!(self.status.code(), 100..=199 | 204 | 304) => {}
+            Some(len) => write!(head, "Content-Length: {}\r\n", len)?,
+            None => write!(head, "Transfer-Encoding: chunked\r\n")?,
+        }
+        write!(head, "\r\n")?;
+        // one vectored write, which is how the connection's writer knows a
+        // new response head from body bytes
+        let mut rest = &head[..];
+        while !rest.is_empty() {
+            match out.write_vectored(&[IoSlice::new(rest)]) {
+                Ok(0) => return Err(std::io::Error::from(std::io::ErrorKind::WriteZero).into()),
+                Ok(n) => rest = &rest[n..],
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+
+    fn compressible(&self) -> bool {
+        let ct = self
+            .headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("Content-Type"))
+            .map_or("", |(_, value)| {
+                value.split(';').next().unwrap_or("").trim()
+            });
+        ct.starts_with("text/")
+            || ct.ends_with("+xml")
+            || ct.ends_with("+json")
+            || matches!(
+                ct,
+                "application/json" | "application/javascript" | "application/xml"
+            )
+    }
+
+    fn not_modified(&self, etag: &str, mtime: u64) -> bool {
+        if let Some(tags) = &self.if_none_match {
+            let opaque = etag.trim_start_matches("W/");
+            return tags
+                .split(',')
+                .map(|t| t.trim())
+                .any(|t| t == "*" || t.trim_start_matches("W/") == opaque);
+        }
+        match self.if_modified_since {
+            Some(since) => since
+                .duration_since(UNIX_EPOCH)
+                .is_ok_and(|since| mtime <= since.as_secs()),
+            None => false,
+        }
+    }
+
+    pub fn chunked<W: Write>(mut self, mut out: W) -> err::Result<ChunkedWriter<W>> {
+        self.version = "HTTP/1.1";
+        self.write_head(&mut out, None)?;
+        Ok(ChunkedWriter::new(out))
+    }
+
+    pub fn write_to(self, out: &mut impl Write) -> err::Result<()> {
+        self.write_with(out, |out, file, len| copy_body(file.take(len), out))
+    }
+
+    // Sends file bodies with the stream's own `write_file`, which is
+    // sendfile(2) for TCP sockets on Linux.
+    pub fn write_to_stream(self, out: &mut impl Stream) -> err::Result<()> {
+        self.write_with(out, |out, file, len| out.write_file(file, len))
+    }
+
+    fn write_with<W: Write>(
+        mut self,
+        out: &mut W,
+        copy_file: impl Fn(&mut W, &mut std::fs::File, u64) -> err::Result<u64>,
+    ) -> err::Result<()> {
+        match std::mem::replace(&mut self.body, Body::Empty) {
+            Body::Empty => self.write_head(out, Some(0))?,
+            Body::Bytes(mut bytes) => {
+                if let (Some(gzip), true) = (self.accept_gzip, self.compressible()) {
+                    self = self.header("Vary", "Accept-Encoding");
+                    if gzip {
+                        bytes = deflate::gzip(&bytes);
+                        self = self.header("Content-Encoding", "gzip");
+                    }
+                }
+                self.write_head(out, Some(bytes.len() as u64))?;
+                out.write_all(&bytes)?;
+            }
+            Body::File(fname) => {
+                let meta = std::fs::metadata(&fname)?;
+                if !meta.is_file() {
+                    return err::input(format!("not a regular file: {}", fname.display()));
+                }
+                let len = meta.len();
+                let modified = meta.modified().ok();
+                let mtime = modified
+                    .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                    .map_or(0, |d| d.as_secs());
+                let etag = format!("W/\"{:x}-{:x}\"", len, mtime);
+                self = self.header("ETag", &etag);
+                if let Some(modified) = modified {
+                    self = self.header("Last-Modified", &date::format(modified));
+                }
+                if self.status == Status::OK && self.not_modified(&etag, mtime) {
+                    self.status = Status::NotModified;
+                    return self.write_head(out, Some(0));
+                }
+
+                let mut file = std::fs::File::open(fname)?;
+                let range = match (&self.range, self.status) {
+                    (Some(r), Status::OK) => parse_range(r, len),
+                    _ => Range::Full,
+                };
+                match range {
+                    Range::Full => {
+                        self.write_head(out, Some(len))?;
+                        copy_file(out, &mut file, len)?;
+                    }
+                    Range::Partial(start, end) => {
+                        let range = format!("bytes {}-{}/{}", start, end, len);
+                        self.status = Status::PartialContent;
+                        self = self.header("Content-Range", &range);
+                        self.write_head(out, Some(end - start + 1))?;
+                        file.seek(SeekFrom::Start(start))?;
+                        copy_file(out, &mut file, end - start + 1)?;
+                    }
+                    Range::Unsatisfiable => {
+                        self.status = Status::RangeNotSatisfiable;
+                        self = self.header("Content-Range", &format!("bytes */{}", len));
+                        self.write_head(out, Some(0))?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+const COPY_BUF: usize = 64 * 1024;
+
+fn copy_body(mut body: impl Read, out: &mut (impl Write + ?Sized)) -> err::Result<u64> {
+    let mut buf = vec![0u8; COPY_BUF];
+    let mut total = 0;
+    loop {
+        let n = match body.read(&mut buf) {
+            Ok(0) => return Ok(total),
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        out.write_all(&buf[..n])?;
+        total += n as u64;
+    }
+}
+
+pub struct ChunkedWriter<W: Write> {
+    inner: Option<W>,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    pub fn new(inner: W) -> ChunkedWriter<W> {
+        ChunkedWriter { inner: Some(inner) }
+    }
+
+    pub fn finish(mut self) -> err::Result<W> {
+        let mut inner = self.inner.take().unwrap();
+        inner.write_all(b"0\r\n\r\n")?;
+        inner.flush()?;
+        Ok(inner)
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // a zero-length chunk would end the body
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let inner = self.inner.as_mut().unwrap();
+        write!(inner, "{:x}\r\n", buf.len())?;
+        inner.write_all(buf)?;
+        inner.write_all(b"\r\n")?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.as_mut().unwrap().flush()
+    }
+}
+
+impl<W: Write> Drop for ChunkedWriter<W> {
+    fn drop(&mut self) {
+        if let Some(mut inner) = self.inner.take() {
+            let _ = inner.write_all(b"0\r\n\r\n");
+        }
+    }
+}
+
+pub fn send_headers(
+    client: &mut impl Write,
+    status: Status,
+    content_type: &str,
+    len: u64,
+) -> err::Result<()> {
+    Response::new(status)
+        .content_type(content_type)
+        .write_head(client, Some(len))
+}
+
+pub fn send_str(
+    mut client: impl Write,
+    status: Status,
+    content_type: &str,
+    content: &str,
+) -> err::Result<()> {
+    Response::new(status)
+        .content_type(content_type)
+        .body_str(content)
+        .write_to(&mut client)
+}
+
+pub fn send_str_for(
+    req: &Req,
+    mut client: impl Write,
+    status: Status,
+    content_type: &str,
+    content: &str,
+) -> err::Result<()> {
+    Response::new(status)
+        .for_request(req)
+        .content_type(content_type)
+        .body_str(content)
+        .write_to(&mut client)
+}
+
+pub fn send_chunked<W: Write>(
+    client: W,
+    status: Status,
+    content_type: &str,
+) -> err::Result<ChunkedWriter<W>> {
+    Response::new(status)
+        .content_type(content_type)
+        .chunked(client)
+}
+
+pub fn send_bytes(
+    mut client: impl Write,
+    status: Status,
+    content_type: &str,
+    content: &[u8],
+) -> err::Result<()> {
+    Response::new(status)
+        .content_type(content_type)
+        .body_bytes(content.to_vec())
+        .write_to(&mut client)
+}
+
+pub fn send_file(
+    mut client: impl Stream,
+    status: Status,
+    content_type: &str,
+    fname: &str,
+) -> err::Result<()> {
+    Response::new(status)
+        .content_type(content_type)
+        .body_file(fname)
+        .write_to_stream(&mut client)
+}
+
+pub fn send_file_for(
+    req: &Req,
+    mut client: impl Stream,
+    content_type: &str,
+    fname: &str,
+) -> err::Result<()> {
+    Response::new(Status::OK)
+        .content_type(content_type)
+        .header("Accept-Ranges", "bytes")
+        .body_file(fname)
+        .for_request(req)
+        .write_to_stream(&mut client)
+}
+
+pub fn send_file_auto(client: impl Stream, status: Status, fname: &str) -> err::Result<()> {
+    send_file(client, status, mime::from_path(fname), fname)
+}
+
+pub fn redirect(mut client: impl Write, status: Status, location: &str) -> err::Result<()> {
+    if !matches!(status.code(), 301 | 302 | 303 | 307 | 308) {
+        return err::input(format!("not a redirect status: {}", status));
+    }
+    if location.contains(['\r', '\n']) {
+        return err::input(format!("bad redirect location: {:?}", location));
+    }
+    Response::new(status)
+        .header("Location", location)
+        .write_to(&mut client)
+}
+
+pub fn unauthorized(mut client: impl Write, realm: &str) -> err::Result<()> {
+    if realm.contains(['\r', '\n', '"', '\\']) {
+        return err::input(format!("bad realm: {:?}", realm));
+    }
+    Response::new(Status::Unauthorized)
+        .header("WWW-Authenticate", &format!("Basic realm=\"{}\"", realm))
+        .content_type("text/plain")
+        .body_str("unauthorized\n")
+        .write_to(&mut client)
+}
+
+pub fn serve_dir(root: &Path) -> impl App {
+    let root = root.to_path_buf();
+    // a miss is left unanswered, so the server sends its not-found page
+    move |req: Req, client: Box<dyn Stream>| match resolve_path(&root, &req.path) {
+        Some(fname) => match fname.to_str() {
+            Some(fname) => send_file_for(&req, client, mime::from_path(fname), fname),
+            None => Ok(()),
+        },
+        None => Ok(()),
+    }
+}
+
+fn resolve_path(root: &Path, path: &str) -> Option<PathBuf> {
+    let rel = Path::new(path.trim_start_matches('/'));
+    if !rel.components().all(|c| matches!(c, Component::Normal(_))) {
+        return None;
+    }
+
+    let root = root.canonicalize().ok()?;
+    let mut fname = root.join(rel).canonicalize().ok()?;
+    if !fname.starts_with(&root) {
+        return None;
+    }
+    if fname.is_dir() {
+        fname.push("index.html");
+    }
+    match fname.is_file() {
+        true => Some(fname),
+        false => None,
+    }
+}
+
+pub trait Stream: Read + Write + Send {
+    fn try_clone(&self) -> std::io::Result<Box<dyn Stream>>;
+
+    // Streams that can't time reads out only manage without a timeout, so
+    // that asking for one fails rather than being quietly ignored.
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        match timeout {
+            Some(_) => Err(std::io::ErrorKind::Unsupported.into()),
+            None => Ok(()),
+        }
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    // Ends the connection in both directions, waking anything blocked on it.
+    fn shutdown(&self) -> std::io::Result<()> {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+
+    // Sends `len` bytes of `file` from its current position.
+    fn write_file(&mut self, file: &mut std::fs::File, len: u64) -> err::Result<u64> {
+        copy_body(Read::take(file, len), self)
+    }
+
+    // Adds a header to the response still to be sent, however the handler
+    // ends up writing it. Only the server's own connections keep it.
+    fn add_header(&mut self, _name: &str, _value: &str) {}
+}
+
+impl Stream for Box<dyn Stream> {
+    fn try_clone(&self) -> std::io::Result<Box<dyn Stream>> {
+        (**self).try_clone()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        (**self).set_read_timeout(timeout)
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        (**self).peer_addr()
+    }
+
+    fn shutdown(&self) -> std::io::Result<()> {
+        (**self).shutdown()
+    }
+
+    fn write_file(&mut self, file: &mut std::fs::File, len: u64) -> err::Result<u64> {
+        (**self).write_file(file, len)
+    }
+
+    fn add_header(&mut self, name: &str, value: &str) {
+        (**self).add_header(name, value)
+    }
+}
+
+#[cfg(target_os = "linux")]
+extern "C" {
+    fn sendfile(out_fd: i32, in_fd: i32, offset: *mut i64, count: usize) -> isize;
+}
+
+impl Stream for TcpStream {
+    fn try_clone(&self) -> std::io::Result<Box<dyn Stream>> {
+        Ok(Box::new(TcpStream::try_clone(self)?))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        TcpStream::peer_addr(self).ok()
+    }
+
+    fn shutdown(&self) -> std::io::Result<()> {
+        TcpStream::shutdown(self, std::net::Shutdown::Both)
+    }
+
+    // Lets the kernel move the file straight to the socket with sendfile(2)
+    // instead of copying it through a userspace buffer.
+    #[cfg(target_os = "linux")]
+    fn write_file(&mut self, file: &mut std::fs::File, len: u64) -> err::Result<u64> {
+        use std::os::unix::io::AsRawFd;
+
+        let mut sent = 0;
+        while sent < len {
+            let count = (len - sent).min(1 << 30) as usize;
+            // SAFETY: both descriptors stay open for the call, and a null
+            // offset has the kernel read from and advance the file position
+            let n = unsafe {
+                sendfile(
+                    self.as_raw_fd(),
+                    file.as_raw_fd(),
+                    std::ptr::null_mut(),
+                    count,
+                )
+            };
+            match n {
+                -1 => {
+                    let e = std::io::Error::last_os_error();
+                    match e.kind() {
+                        std::io::ErrorKind::Interrupted => continue,
+                        // EINVAL or ENOSYS: not supported for this pair of files
+                        std::io::ErrorKind::InvalidInput | std::io::ErrorKind::Unsupported
+                            if sent == 0 =>
+                        {
+                            return copy_body(Read::take(file, len), self);
+                        }
+                        _ => return Err(e.into()),
+                    }
+                }
+                // the file got shorter since it was measured
+                0 => break,
+                n => sent += n as u64,
+            }
+        }
+        Ok(sent)
+    }
+}
+
+pub trait App: Fn(Req, Box<dyn Stream>) -> err::Result<()> + Send + Sync {}
+
+impl<F: Fn(Req, Box<dyn Stream>) -> err::Result<()> + Send + Sync> App for F {}
+
+const ACCEPT_POLL: Duration = Duration::from_millis(10);
+const DEFAULT_SERVER_NAME: &str = "webd 0.1";
+const DEFAULT_READ_BUFFER: usize = 8 * 1024;
+
+pub struct RequestLog<'a> {
+    pub verb: Verb,
+    pub path: &'a str,
+    pub version: &'a str,
+    pub remote_addr: Option<SocketAddr>,
+    pub status: Option<Status>,
+    pub bytes: Option<u64>,
+    pub duration: Duration,
+}
+
+pub enum LogEvent<'a> {
+    Listening(String),
+    Request(RequestLog<'a>),
+    Error(String),
+    ShuttingDown,
+}
+
+pub type Logger = Arc<dyn Fn(&LogEvent) + Send + Sync>;
+pub type ErrorPage = Arc<dyn Fn(&err::Error) -> Response + Send + Sync>;
+pub type NotFoundPage = Arc<dyn Fn(&Req) -> Response + Send + Sync>;
+pub type ExpectCheck = Arc<dyn Fn(&Req) -> Option<Response> + Send + Sync>;
+pub type StreamWrapper =
+    Arc<dyn Fn(Box<dyn Stream>) -> std::io::Result<Box<dyn Stream>> + Send + Sync>;
+
+pub struct Config {
+    pub workers: usize,
+    pub read_timeout: Option<Duration>,
+    // how long a kept-alive connection may sit idle waiting for its next
+    // request; read_timeout still applies once that request starts
+    pub keep_alive_timeout: Option<Duration>,
+    pub logger: Option<Logger>,
+    pub server_name: Option<String>,
+    pub limits: Limits,
+    pub keep_alive: bool,
+    pub on_bad_request: Option<ErrorPage>,
+    pub on_not_found: Option<NotFoundPage>,
+    pub on_error: Option<ErrorPage>,
+    pub on_expect: Option<ExpectCheck>,
+    pub stream_body_above: Option<usize>,
+    pub drain_timeout: Option<Duration>,
+    pub read_buffer_size: usize,
+    // Runs on the worker for each accepted connection, before any request is
+    // read, e.g. to do a TLS handshake; the server then reads and writes
+    // through the stream it returns.
+    pub wrap_stream: Option<StreamWrapper>,
+}
+
+impl Config {
+    fn log(&self, event: LogEvent) {
+        if let Some(logger) = &self.logger {
+            logger(&event);
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            workers: std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(4),
+            read_timeout: Some(Duration::from_secs(30)),
+            keep_alive_timeout: Some(Duration::from_secs(5)),
+            logger: None,
+            server_name: Some(DEFAULT_SERVER_NAME.to_string()),
+            limits: Limits::default(),
+            keep_alive: true,
+            on_bad_request: None,
+            on_not_found: None,
+            on_error: None,
+            on_expect: None,
+            stream_body_above: None,
+            drain_timeout: None,
+            read_buffer_size: DEFAULT_READ_BUFFER,
+            wrap_stream: None,
+        }
+    }
+}
+
+enum Endpoint {
+    Tcp(String),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+pub struct Server {
+    endpoint: Endpoint,
+    config: Config,
+    shutdown: Option<Receiver<()>>,
+}
+
+impl Server {
+    pub fn new(endpoint: &str) -> Server {
+        Server {
+            endpoint: Endpoint::Tcp(endpoint.to_string()),
+            config: Config::default(),
+            shutdown: None,
+        }
+    }
+
+    #[cfg(unix)]
+    pub fn bind_unix(path: impl AsRef<Path>) -> Server {
+        Server {
+            endpoint: Endpoint::Unix(path.as_ref().to_path_buf()),
+            config: Config::default(),
+            shutdown: None,
+        }
+    }
+
+    pub fn with_config(mut self, config: Config) -> Server {
+        self.config = config;
+        self
+    }
+
+    pub fn workers(mut self, workers: usize) -> Server {
+        self.config.workers = workers;
+        self
+    }
+
+    pub fn read_timeout(mut self, timeout: Option<Duration>) -> Server {
+        self.config.read_timeout = timeout;
+        self
+    }
+
+    pub fn keep_alive_timeout(mut self, timeout: Option<Duration>) -> Server {
+        self.config.keep_alive_timeout = timeout;
+        self
+    }
+
+    pub fn limits(mut self, limits: Limits) -> Server {
+        self.config.limits = limits;
+        self
+    }
+
+    pub fn server_name(mut self, name: Option<&str>) -> Server {
+        self.config.server_name = name.map(|n| n.to_string());
+        self
+    }
+
+    pub fn keep_alive(mut self, keep_alive: bool) -> Server {
+        self.config.keep_alive = keep_alive;
+        self
+    }
+
+    pub fn logger(mut self, logger: impl Fn(&LogEvent) + Send + Sync + 'static) -> Server {
+        self.config.logger = Some(Arc::new(logger));
+        self
+    }
+
+    pub fn on_bad_request(
+        mut self,
+        page: impl Fn(&err::Error) -> Response + Send + Sync + 'static,
+    ) -> Server {
+        self.config.on_bad_request = Some(Arc::new(page));
+        self
+    }
+
+    pub fn on_not_found(
+        mut self,
+        page: impl Fn(&Req) -> Response + Send + Sync + 'static,
+    ) -> Server {
+        self.config.on_not_found = Some(Arc::new(page));
+        self
+    }
+
+    pub fn on_error(
+        mut self,
+        page: impl Fn(&err::Error) -> Response + Send + Sync + 'static,
+    ) -> Server {
+        self.config.on_error = Some(Arc::new(page));
+        self
+    }
+
+    pub fn on_expect(
+        mut self,
+        check: impl Fn(&Req) -> Option<Response> + Send + Sync + 'static,
+    ) -> Server {
+        self.config.on_expect = Some(Arc::new(check));
+        self
+    }
+
+    pub fn stream_body_above(mut self, size: Option<usize>) -> Server {
+        self.config.stream_body_above = size;
+        self
+    }
+
+    // After shutdown, how long to wait for open connections to finish before
+    // cutting them off. Without it the server waits for as long as they take.
+    pub fn drain_timeout(mut self, timeout: Option<Duration>) -> Server {
+        self.config.drain_timeout = timeout;
+        self
+    }
+
+    pub fn read_buffer_size(mut self, size: usize) -> Server {
+        self.config.read_buffer_size = size;
+        self
+    }
+
+    pub fn wrap_stream(
+        mut self,
+        wrap: impl Fn(Box<dyn Stream>) -> std::io::Result<Box<dyn Stream>> + Send + Sync + 'static,
+    ) -> Server {
+        self.config.wrap_stream = Some(Arc::new(wrap));
+        self
+    }
+
+    pub fn shutdown(mut self, shutdown: Receiver<()>) -> Server {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn run(self, app: impl App + 'static) -> err::Result<()> {
+        let (_tx, never) = channel();
+        let shutdown = self.shutdown.unwrap_or(never);
+        match self.endpoint {
+            Endpoint::Tcp(addr) => serve_on(TcpListener::bind(addr)?, self.config, app, shutdown),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => serve_on(bind_unix(&path)?, self.config, app, shutdown),
+        }
+    }
+}
+
+pub fn serve(endpoint: &str, app: impl App + 'static) -> err::Result<()> {
+    Server::new(endpoint).run(app)
+}
+
+pub fn serve_with(endpoint: &str, config: Config, app: impl App + 'static) -> err::Result<()> {
+    Server::new(endpoint).with_config(config).run(app)
+}
+
+pub fn serve_with_shutdown(
+    endpoint: &str,
+    app: impl App + 'static,
+    shutdown: Receiver<()>,
+) -> err::Result<()> {
+    Server::new(endpoint).shutdown(shutdown).run(app)
+}
+
+trait Listener {
+    type Conn: Stream + 'static;
+
+    fn accept_conn(&self) -> std::io::Result<Self::Conn>;
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()>;
+    fn describe(&self) -> String;
+}
+
+impl Listener for TcpListener {
+    type Conn = TcpStream;
+
+    fn accept_conn(&self) -> std::io::Result<TcpStream> {
+        let (client, _) = self.accept()?;
+        client.set_nonblocking(false)?;
+        Ok(client)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()> {
+        TcpListener::set_nonblocking(self, nonblocking)
+    }
+
+    fn describe(&self) -> String {
+        match self.local_addr() {
+            Ok(addr) => addr.to_string(),
+            Err(_) => "tcp".to_string(),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Stream for UnixStream {
+    fn try_clone(&self) -> std::io::Result<Box<dyn Stream>> {
+        Ok(Box::new(UnixStream::try_clone(self)?))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn shutdown(&self) -> std::io::Result<()> {
+        UnixStream::shutdown(self, std::net::Shutdown::Both)
+    }
+}
+
+#[cfg(unix)]
+impl Listener for UnixListener {
+    type Conn = UnixStream;
+
+    fn accept_conn(&self) -> std::io::Result<UnixStream> {
+        let (client, _) = self.accept()?;
+        client.set_nonblocking(false)?;
+        Ok(client)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()> {
+        UnixListener::set_nonblocking(self, nonblocking)
+    }
+
+    fn describe(&self) -> String {
+        match self
+            .local_addr()
+            .ok()
+            .and_then(|a| a.as_pathname().map(|p| p.to_owned()))
+        {
+            Some(path) => path.display().to_string(),
+            None => "unix socket".to_string(),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn bind_unix(path: &Path) -> err::Result<UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+
+    // a socket file left behind by a previous run would make bind fail
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        if meta.file_type().is_socket() {
+            std::fs::remove_file(path)?;
+        }
+    }
+    Ok(UnixListener::bind(path)?)
+}
+
+#[cfg(unix)]
+pub fn serve_unix(path: impl AsRef<Path>, app: impl App + 'static) -> err::Result<()> {
+    Server::bind_unix(path).run(app)
+}
+
+fn serve_on(
+    server: impl Listener,
+    config: Config,
+    app: impl App + 'static,
+    shutdown: Receiver<()>,
+) -> err::Result<()> {
+    let app = Arc::new(app);
+    let config = Arc::new(config);
+    let pool = pool::ThreadPool::new(config.workers);
+    // a handle on each open connection, to cut off any outliving the drain
+    let live: Arc<Mutex<HashMap<u64, Box<dyn Stream>>>> = Default::default();
+    let mut next_id = 0;
+    server.set_nonblocking(true)?;
+    config.log(LogEvent::Listening(server.describe()));
+
+    while let Err(TryRecvError::Empty) = shutdown.try_recv() {
+        let client = match server.accept_conn() {
+            Ok(client) => client,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                std::thread::sleep(ACCEPT_POLL);
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let id = next_id;
+        next_id += 1;
+        if config.drain_timeout.is_some() {
+            if let Ok(conn) = client.try_clone() {
+                live.lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .insert(id, conn);
+            }
+        }
+
+        let app = app.clone();
+        let config = config.clone();
+        let live = live.clone();
+        pool.execute(move || {
+            let result = match &config.wrap_stream {
+                // the read timeout bounds the handshake too
+                Some(wrap) => client
+                    .set_read_timeout(config.read_timeout)
+                    .and_then(|_| wrap(Box::new(client)))
+                    .map_err(err::Error::from)
+                    .and_then(|client| handle_client(client, &config, &*app)),
+                None => handle_client(client, &config, &*app),
+            };
+            match result {
+                Err(e) if e.is_timeout() => {
+                    config.log(LogEvent::Error("client timed out".to_string()))
+                }
+                Err(e) => config.log(LogEvent::Error(format!("problem handling client: {}", e))),
+                Ok(_) => {}
+            }
+            live.lock().unwrap_or_else(|e| e.into_inner()).remove(&id);
+        });
+    }
 
+    config.log(LogEvent::ShuttingDown);
+    if let Some(timeout) = config.drain_timeout {
+        if !pool.wait_idle(timeout) {
+            let live = live.lock().unwrap_or_else(|e| e.into_inner());
+            config.log(LogEvent::Error(format!(
+                "dropping {} connections after the drain timeout",
+                live.len()
+            )));
+            for conn in live.values() {
+                let _ = conn.shutdown();
+            }
+            pool.detach();
+        }
+    }
     Ok(())
 }
 
-pub type App = fn(Req, TcpStream) -> err::Result<()>;
-
-pub fn serve(endpoint: &str, app: App) -> err::Result<()> {
-    let server = TcpListener::bind(endpoint)?;
-    println!("bound to {}", endpoint);
+// Reads the next request. A client that sent `Expect: 100-continue` is told
+// to go ahead with the body unless `on_expect` answers with a final response,
+// in which case the body is never read and None is returned.
+fn read_request<S: Stream>(
+    stream: &mut BufReader<S>,
+    config: &Config,
+    start: Instant,
+) -> err::Result<Option<Req>> {
+    let mut req = Req::parse_head(&mut *stream, &config.limits)?;
+    req.remote_addr = stream.get_ref().peer_addr();
+    req.read_buffer = config.read_buffer_size;
+    let stream_body = req.streams_body(config.stream_body_above)?;
+    if req.expects_continue() {
+        // refuse an oversized body before the client starts sending it
+        if !stream_body {
+            req.buffered_length(&config.limits)?;
+        }
+        if let Some(resp) = config.on_expect.as_ref().and_then(|check| check(&req)) {
+            let head_only = req.verb == Verb::Head;
+            let client = stream.get_ref().try_clone()?;
+            let server = config.server_name.as_deref();
+            let mut client = exchange::Exchange::new(client, server, head_only);
+            resp.write_to(&mut client)?;
+            log_request(
+                config,
+                req.verb,
+                &req.path,
+                &req.version,
+                req.remote_addr,
+                client.state().response(),
+                start,
+            );
+            return Ok(None);
+        }
+        stream
+            .get_mut()
+            .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+        stream.get_mut().flush()?;
+    }
+    if stream_body {
+        // whatever was read past the head already belongs to the body
+        let read_ahead = stream.buffer().to_vec();
+        stream.consume(read_ahead.len());
+        let rest = stream.get_ref().try_clone()?;
+        req.body_source = Some(Box::new(std::io::Cursor::new(read_ahead).chain(rest)));
+    } else {
+        req.read_body(&mut *stream, &config.limits)?;
+    }
+    Ok(Some(req))
+}
 
-    for client in server.incoming() {
-        let mut stream = BufReader::new(client?);
-        let req = match Req::parse(&mut stream) {
-            Ok(r) => r,
+fn handle_client(
+    client: impl Stream + 'static,
+    config: &Config,
+    app: &impl App,
+) -> err::Result<()> {
+    let mut stream = BufReader::with_capacity(config.read_buffer_size, client);
+    let mut first = true;
+    loop {
+        let idle = if first {
+            config.read_timeout
+        } else {
+            config.keep_alive_timeout
+        };
+        stream.get_ref().set_read_timeout(idle)?;
+        match stream.fill_buf() {
+            Ok([]) => return Ok(()),
+            Ok(_) => {}
             Err(e) => {
-                println!("problem with request: {}", e);
-                match e {
-                    err::Error::Input(mut msg) => {
-                        msg.push('\n');
-                        let r = send_str(
-                            stream.into_inner(),
-                            Status::BadRequest,
-                            "text/plain",
-                            msg.as_str(),
-                        );
-                        match r {
-                            Err(e) => println!("problem sending: {}", e),
-                            Ok(_) => {}
-                        }
-                        continue;
+                let e = err::Error::from(e);
+                // an idle keep-alive connection is simply closed
+                return if e.is_disconnect() || (!first && e.is_timeout()) {
+                    Ok(())
+                } else {
+                    Err(e)
+                };
+            }
+        }
+        if !first {
+            stream.get_ref().set_read_timeout(config.read_timeout)?;
+        }
+        first = false;
+
+        let start = Instant::now();
+        let req = match read_request(&mut stream, config, start) {
+            Ok(Some(r)) => r,
+            Ok(None) => return Ok(()),
+            Err(e) if e.is_timeout() => return Err(e),
+            // the client went away mid-request; nobody is left to tell
+            Err(e) if e.is_disconnect() || e.kind() == Some(std::io::ErrorKind::UnexpectedEof) => {
+                return Ok(())
+            }
+            Err(e) => {
+                config.log(LogEvent::Error(format!("problem with request: {}", e)));
+                let reply = match &e {
+                    err::Error::Input(msg) => Some((Status::BadRequest, msg)),
+                    err::Error::TooLarge(msg) => Some((Status::PayloadTooLarge, msg)),
+                    _ => None,
+                };
+                if let Some((status, msg)) = reply {
+                    let resp = match &config.on_bad_request {
+                        Some(page) => page(&e),
+                        None => Response::new(status)
+                            .content_type("text/plain")
+                            .body_str(&format!("{}\n", msg)),
+                    };
+                    let client = Box::new(stream.into_inner());
+                    let server = config.server_name.as_deref();
+                    if let Err(e) =
+                        resp.write_to(&mut exchange::Exchange::new(client, server, false))
+                    {
+                        config.log(LogEvent::Error(format!("problem sending: {}", e)));
                     }
-                    _ => {}
                 }
-                continue;
+                return Ok(());
             }
         };
 
-        println!("{} {} {}", req.version, req.verb, req.path);
+        stream.get_ref().set_read_timeout(None)?;
+        // a streamed body leaves the connection in the handler's hands
+        let keep_alive = config.keep_alive && req.keep_alive() && req.body_source.is_none();
+        // what was read past the request belongs to the next one, unless
+        // there is none, as after an upgrade, when it's the handler's
+        let mut read_ahead = Vec::new();
+        if !keep_alive {
+            read_ahead = stream.buffer().to_vec();
+            stream.consume(read_ahead.len());
+        }
+        let client = stream.get_ref().try_clone()?;
+        if !run_app(config, app, req, client, read_ahead, keep_alive, start) {
+            return Ok(());
+        }
+    }
+}
 
-        app(req, stream.into_inner())?;
+fn run_app(
+    config: &Config,
+    app: &impl App,
+    req: Req,
+    client: Box<dyn Stream>,
+    read_ahead: Vec<u8>,
+    keep_alive: bool,
+    start: Instant,
+) -> bool {
+    let (verb, path, version) = (req.verb, req.path.clone(), req.version.clone());
+    let remote_addr = req.remote_addr;
+
+    // HEAD requests reuse GET handlers, with only the head sent
+    let server = config.server_name.as_deref();
+    let mut client =
+        exchange::Exchange::new(client, server, verb == Verb::Head).read_ahead(read_ahead);
+    if keep_alive {
+        client = client.keep_alive(&version);
     }
+    let state = client.state();
+    // a second handle on the connection for the not-found or error page,
+    // should the handler finish without sending a response
+    let fallback = client.duplicate().ok();
+    let page_req = config.on_not_found.as_ref().map(|_| req.head_copy());
+    let send_error = |fallback: Option<exchange::Exchange>, e: &err::Error| {
+        if let (Some(mut client), true) = (fallback, state.unanswered()) {
+            let resp = match &config.on_error {
+                Some(page) => page(e),
+                None => Response::new(Status::InternalServerError)
+                    .content_type("text/plain")
+                    .body_str("internal server error\n"),
+            };
+            if let Err(e) = resp.write_to(&mut client) {
+                config.log(LogEvent::Error(format!("problem sending: {}", e)));
+            }
+        }
+    };
 
-    Ok(())
+    let ok = match catch_unwind(AssertUnwindSafe(|| app(req, Box::new(client)))) {
+        Ok(Ok(_)) => {
+            // only once the handler has let go of the connection without
+            // answering; one that handed it to another thread may yet do so
+            let dropped = fallback.as_ref().is_some_and(|c| c.last_handle());
+            if let (Some(mut client), true) = (fallback, dropped && state.unanswered()) {
+                let resp = match (&config.on_not_found, &page_req) {
+                    (Some(page), Some(req)) => page(req),
+                    _ => Response::new(Status::NotFound)
+                        .content_type("text/plain")
+                        .body_str("not found\n"),
+                };
+                if let Err(e) = resp.write_to(&mut client) {
+                    config.log(LogEvent::Error(format!("problem sending: {}", e)));
+                }
+            }
+            true
+        }
+        Ok(Err(e)) if e.is_disconnect() => {
+            config.log(LogEvent::Error(format!("client disconnected: {}", e)));
+            false
+        }
+        Ok(Err(e)) => {
+            config.log(LogEvent::Error(format!("problem in handler: {}", e)));
+            send_error(fallback, &e);
+            false
+        }
+        Err(_) => {
+            config.log(LogEvent::Error("handler panicked".to_string()));
+            send_error(fallback, &std::io::Error::other("handler panicked").into());
+            false
+        }
+    };
+
+    log_request(
+        config,
+        verb,
+        &path,
+        &version,
+        remote_addr,
+        state.response(),
+        start,
+    );
+    ok && state.reusable()
 }
 
+fn log_request(
+    config: &Config,
+    verb: Verb,
+    path: &str,
+    version: &str,
+    remote_addr: Option<SocketAddr>,
+    response: Option<(Status, Option<u64>)>,
+    start: Instant,
+) {
+    config.log(LogEvent::Request(RequestLog {
+        verb,
+        path,
+        version,
+        remote_addr,
+        status: response.map(|(status, _)| status),
+        bytes: response.and_then(|(_, bytes)| bytes),
+        duration: start.elapsed(),
+    }));
+}
+
+#[derive(Clone, Copy, PartialEq)]
 enum OpCode {
     Continuation,
     Text,
@@ -234,6 +2312,10 @@ impl OpCode {
         }
     }
 
+    fn is_control(&self) -> bool {
+        matches!(self, OpCode::Close | OpCode::Ping | OpCode::Pong)
+    }
+
     fn as_byte(&self) -> u8 {
         match self {
             OpCode::Continuation => 0x0,
@@ -246,8 +2328,12 @@ impl OpCode {
     }
 }
 
+// The RSV1 bit, which marks a message compressed with permessage-deflate.
+const RSV1: u8 = 0b100;
+
 struct FrameHeader {
     fin: bool,
+    rsv: u8,
     opcode: OpCode,
     header_len: usize,
     payload_len: usize,
@@ -259,11 +2345,12 @@ impl FrameHeader {
         self.header_len + self.payload_len
     }
 
-    pub fn parse(buf: &[u8]) -> Option<FrameHeader> {
+    pub fn parse(buf: &[u8]) -> err::Result<Option<FrameHeader>> {
         let n = buf.len();
         let mut used = 2;
         if n > 1 {
             let fin = (buf[0] & 0x80) == 0x80;
+            let rsv = (buf[0] >> 4) & 0x7;
             let opcode = buf[0] & 0x0f;
             if let Some(opcode) = OpCode::parse(opcode) {
                 let mask = (buf[1] & 0x80) == 0x80;
@@ -271,28 +2358,35 @@ impl FrameHeader {
                 match payload_len {
                     126 => {
                         if n < 4 {
-                            return None;
+                            return Ok(None);
                         }
                         payload_len = ((buf[2] as usize) << 8) | buf[3] as usize;
                         used += 2;
                     }
                     127 => {
                         if n < 10 {
-                            return None;
+                            return Ok(None);
+                        }
+                        let mut len = [0u8; 8];
+                        len.clone_from_slice(&buf[2..10]);
+                        let len = u64::from_be_bytes(len);
+                        if len >> 63 != 0 {
+                            return err::input("payload length has the high bit set".to_string());
                         }
-                        payload_len = ((buf[2] as usize) << 7 * 8)
-                            | ((buf[3] as usize) << 6 * 8)
-                            | ((buf[4] as usize) << 5 * 8)
-                            | ((buf[5] as usize) << 4 * 8)
-                            | ((buf[6] as usize) << 3 * 8)
-                            | ((buf[7] as usize) << 2 * 8)
-                            | ((buf[8] as usize) << 8)
-                            | buf[9] as usize;
+                        payload_len = match usize::try_from(len) {
+                            Ok(l) => l,
+                            Err(_) => {
+                                return err::input(format!("payload length too big: {}", len))
+                            }
+                        };
                         used += 8;
                     }
                     _ => {}
                 }
                 let masking_key = if mask {
+                    if n < used + 4 {
+                        return Ok(None);
+                    }
                     let mut key = [0u8; 4];
                     key.clone_from_slice(&buf[used..used + 4]);
                     used += 4;
@@ -300,42 +2394,36 @@ impl FrameHeader {
                 } else {
                     None
                 };
-                Some(FrameHeader {
+                Ok(Some(FrameHeader {
                     fin,
+                    rsv,
                     opcode,
                     header_len: used,
                     payload_len,
                     masking_key,
-                })
+                }))
             } else {
-                None
+                err::input(format!("unknown opcode: {:#x}", opcode))
             }
         } else {
-            None
+            Ok(None)
         }
     }
 
-    fn unmask(&self, buf: &[u8]) -> Vec<u8> {
-        let mut vs = buf.to_vec();
-        match self.masking_key {
-            Some(key) => {
-                for i in 0..vs.len() {
-                    vs[i] ^= key[i % 4];
-                }
-                vs
+    fn unmask_in_place(&self, buf: &mut [u8]) {
+        if let Some(key) = self.masking_key {
+            for (b, k) in buf.iter_mut().zip(key.iter().cycle()) {
+                *b ^= k;
             }
-            None => vs,
         }
     }
 
-    fn write(&self, out: &mut impl Write) -> err::Result<usize> {
-        let mut buf = Vec::with_capacity(self.frame_len());
-
+    fn encode(&self, buf: &mut Vec<u8>) {
         let b = match self.fin {
             false => 0u8,
             true => 0x80,
         };
-        let b = b | self.opcode.as_byte();
+        let b = b | self.rsv << 4 | self.opcode.as_byte();
         buf.push(b);
 
         let b = match self.masking_key.is_some() {
@@ -345,17 +2433,17 @@ impl FrameHeader {
 
         if self.payload_len > u16::MAX as usize {
             buf.push(b | 127);
-            buf.push((self.payload_len >> 7 * 8) as u8);
-            buf.push((self.payload_len >> 6 * 8) as u8);
-            buf.push((self.payload_len >> 5 * 8) as u8);
-            buf.push((self.payload_len >> 4 * 8) as u8);
-            buf.push((self.payload_len >> 3 * 8) as u8);
-            buf.push((self.payload_len >> 2 * 8) as u8);
-            buf.push((self.payload_len >> 1 * 8) as u8);
+            buf.push((self.payload_len >> (7 * 8)) as u8);
+            buf.push((self.payload_len >> (6 * 8)) as u8);
+            buf.push((self.payload_len >> (5 * 8)) as u8);
+            buf.push((self.payload_len >> (4 * 8)) as u8);
+            buf.push((self.payload_len >> (3 * 8)) as u8);
+            buf.push((self.payload_len >> (2 * 8)) as u8);
+            buf.push((self.payload_len >> 8) as u8);
             buf.push(self.payload_len as u8);
         } else if self.payload_len > 125 {
             buf.push(b | 126);
-            buf.push((self.payload_len >> 1 * 8) as u8);
+            buf.push((self.payload_len >> 8) as u8);
             buf.push(self.payload_len as u8);
         } else {
             buf.push(b | (self.payload_len as u8));
@@ -370,12 +2458,14 @@ impl FrameHeader {
                 buf.push(arr[3]);
             }
         }
-
-        Ok(out.write(&buf[..])?)
     }
 
-    pub fn final_text(payload_len: usize, masking_key: Option<[u8; 4]>) -> FrameHeader {
-        let header_fixed = 1;
+    pub fn final_frame(
+        opcode: OpCode,
+        payload_len: usize,
+        masking_key: Option<[u8; 4]>,
+    ) -> FrameHeader {
+        let header_fixed = 2;
 
         let payload_extra = if payload_len > u16::MAX as usize {
             8
@@ -394,7 +2484,8 @@ impl FrameHeader {
 
         FrameHeader {
             fin: true,
-            opcode: OpCode::Text,
+            rsv: 0,
+            opcode,
             header_len,
             payload_len,
             masking_key,
@@ -408,82 +2499,621 @@ pub enum Payload {
     Bin(Vec<u8>),
 }
 
+// A message whose first frames have arrived. Uncompressed text is checked
+// as it comes, `valid_utf8` bytes of it so far, so a bad sequence fails the
+// connection without waiting for the rest of the message.
+struct Fragmented {
+    opcode: OpCode,
+    compressed: bool,
+    data: Vec<u8>,
+    valid_utf8: usize,
+}
+
+impl Fragmented {
+    fn check_utf8(&mut self) -> bool {
+        if self.opcode != OpCode::Text || self.compressed {
+            return true;
+        }
+        match std::str::from_utf8(&self.data[self.valid_utf8..]) {
+            Ok(s) => self.valid_utf8 += s.len(),
+            // a sequence cut short by the end of the frame is fine for now
+            Err(e) if e.error_len().is_none() => self.valid_utf8 += e.valid_up_to(),
+            Err(_) => return false,
+        }
+        true
+    }
+}
+
 pub struct WebSocket {
     req: Req,
-    client: BufReader<TcpStream>,
+    client: BufReader<Box<dyn Stream>>,
     open: bool,
+    last_pong: Option<Instant>,
+    fragments: Option<Fragmented>,
+    peer_close: Option<(u16, String)>,
+    pending: Vec<u8>,
+    max_message_size: usize,
+    protocol: Option<String>,
+    writer: Option<WsWriter>,
+    scratch: Vec<u8>,
+    compressor: Option<deflate::Compressor>,
+    keepalive: Option<Duration>,
+    polling: bool,
+    ping_sent: bool,
+    send: SendOptions,
+    inflater: Option<Inflater>,
 }
 
 impl WebSocket {
-    fn new(req: Req, client: BufReader<TcpStream>) -> WebSocket {
+    fn new(req: Req, client: BufReader<Box<dyn Stream>>) -> WebSocket {
         WebSocket {
             req,
             client,
             open: true,
+            last_pong: None,
+            fragments: None,
+            peer_close: None,
+            pending: Vec::new(),
+            max_message_size: 4 * 1024 * 1024,
+            protocol: None,
+            writer: None,
+            scratch: Vec::new(),
+            compressor: None,
+            keepalive: None,
+            polling: false,
+            ping_sent: false,
+            send: SendOptions::default(),
+            inflater: None,
         }
     }
 
-    pub fn recv(&mut self) -> err::Result<Option<Payload>> {
-        if !self.open {
-            return Ok(None);
-        }
-
-        println!("recv from {}", self.req.path);
+    // Pings the peer after `interval` without any incoming data and closes
+    // the connection with 1011 if nothing arrives for another `interval`.
+    pub fn set_keepalive(&mut self, interval: Option<Duration>) -> err::Result<()> {
+        self.client.get_ref().set_read_timeout(interval)?;
+        self.keepalive = interval;
+        self.ping_sent = false;
+        Ok(())
+    }
 
-        let buf = self.client.fill_buf()?;
-        let hdr = match FrameHeader::parse(buf) {
-            Some(h) => h,
-            None => return Ok(None),
+    // Once split, every frame from either half is written under the writer's
+    // lock, so frames never interleave. The reader still answers pings and
+    // close frames itself, in the order they arrive.
+    pub fn split(mut self) -> err::Result<(WsReader, WsWriter)> {
+        let writer = WsWriter {
+            out: Arc::new(Mutex::new(WsOut {
+                client: self.client.get_ref().try_clone()?,
+                scratch: Vec::new(),
+                compressor: self.compressor.take(),
+                send: self.send,
+            })),
+            closed: Arc::new(AtomicBool::new(!self.open)),
         };
+        self.writer = Some(writer.clone());
+        Ok((WsReader { ws: self }, writer))
+    }
 
-        if buf.len() < hdr.frame_len() {
-            return Ok(None);
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_deref()
+    }
+
+    pub fn request(&self) -> &Req {
+        &self.req
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+
+    // Messages longer than `size` go out as a run of frames of at most
+    // `size` bytes each. Control frames are always sent whole.
+    pub fn set_fragment_size(&mut self, size: Option<usize>) {
+        self.send.fragment_size = size;
+        if let Some(writer) = &self.writer {
+            writer.set_fragment_size(size);
         }
+    }
 
-        if !hdr.fin {
-            todo!("continuations");
+    // Yields each message until the connection closes. An error is the last
+    // item.
+    pub fn messages(&mut self) -> Messages<'_> {
+        Messages {
+            ws: self,
+            done: false,
         }
+    }
 
-        let result = match hdr.opcode {
-            OpCode::Continuation => {
-                todo!("got a continuation");
+    pub fn recv(&mut self) -> err::Result<Option<Payload>> {
+        loop {
+            if !self.open {
+                return Ok(None);
             }
-            OpCode::Text => {
-                let s = String::from_utf8(hdr.unmask(&buf[hdr.header_len..]))?;
-                Ok(Some(Payload::Str(s)))
+
+            let hdr = match self.next_header()? {
+                Some(h) => h,
+                None => return Ok(None),
+            };
+
+            if hdr.masking_key.is_none() {
+                return self.fail(1002, "unmasked client frame");
             }
-            OpCode::Binary => Ok(Some(Payload::Bin(hdr.unmask(&buf[hdr.header_len..])))),
-            OpCode::Close => {
-                self.open = false;
-                Ok(None)
+
+            // RSV1 is only meaningful on the first frame of a compressed message
+            let allowed_rsv = match (&self.inflater, hdr.opcode) {
+                (Some(_), OpCode::Text | OpCode::Binary) => RSV1,
+                _ => 0,
+            };
+            if hdr.rsv & !allowed_rsv != 0 {
+                return self.fail(1002, "unexpected reserved bits");
             }
-            OpCode::Ping => {
-                todo!("send pong");
+
+            if hdr.opcode.is_control() && hdr.payload_len > 125 {
+                return self.fail(1002, "control frame too long");
             }
-            OpCode::Pong => {
-                todo!("nothing?");
+            if hdr.opcode.is_control() && !hdr.fin {
+                return self.fail(1002, "fragmented control frame");
             }
-        };
+            match (&self.fragments, hdr.opcode) {
+                (None, OpCode::Continuation) => {
+                    return self.fail(1002, "continuation without a message")
+                }
+                (Some(_), OpCode::Text | OpCode::Binary) => {
+                    return self.fail(1002, "new message inside a fragmented one")
+                }
+                _ => {}
+            }
+
+            let buffered = match &self.fragments {
+                Some(f) if hdr.opcode == OpCode::Continuation => f.data.len(),
+                _ => 0,
+            };
+            if buffered.saturating_add(hdr.payload_len) > self.max_message_size {
+                return self.fail(1009, "message too big");
+            }
+
+            while self.pending.len() < hdr.frame_len() {
+                if self.fill()? == 0 {
+                    self.open = false;
+                    return Ok(None);
+                }
+            }
+
+            // hand the read buffer itself over as the payload when it holds
+            // exactly one frame, which is the common case
+            let rest = self.pending.split_off(hdr.frame_len());
+            let mut payload = std::mem::replace(&mut self.pending, rest);
+            payload.drain(..hdr.header_len);
+            hdr.unmask_in_place(&mut payload);
+
+            match hdr.opcode {
+                OpCode::Text | OpCode::Binary if !hdr.fin => {
+                    let mut fragments = Fragmented {
+                        opcode: hdr.opcode,
+                        compressed: hdr.rsv & RSV1 != 0,
+                        data: payload,
+                        valid_utf8: 0,
+                    };
+                    if !fragments.check_utf8() {
+                        return self.fail(1007, "invalid utf-8 in text message");
+                    }
+                    self.fragments = Some(fragments);
+                }
+                OpCode::Continuation => {
+                    let mut fragments = match self.fragments.take() {
+                        Some(f) => f,
+                        None => return self.fail(1002, "continuation without a message"),
+                    };
+                    fragments.data.extend_from_slice(&payload);
+                    if !hdr.fin {
+                        if !fragments.check_utf8() {
+                            return self.fail(1007, "invalid utf-8 in text message");
+                        }
+                        self.fragments = Some(fragments);
+                        continue;
+                    }
+                    let Fragmented {
+                        opcode,
+                        compressed,
+                        data,
+                        ..
+                    } = fragments;
+                    return self.message(opcode, compressed, data).map(Some);
+                }
+                OpCode::Text | OpCode::Binary => {
+                    return self
+                        .message(hdr.opcode, hdr.rsv & RSV1 != 0, payload)
+                        .map(Some);
+                }
+                OpCode::Close => {
+                    let (code, reason) = match payload.len() {
+                        0 => (1000, String::new()),
+                        1 => return self.fail(1002, "one byte close payload"),
+                        _ => match String::from_utf8(payload[2..].to_vec()) {
+                            Ok(reason) => (u16::from_be_bytes([payload[0], payload[1]]), reason),
+                            Err(_) => return self.fail(1007, "invalid utf-8 in close reason"),
+                        },
+                    };
+                    if !close_code_allowed(code) {
+                        return self.fail(1002, &format!("bad close code {}", code));
+                    }
+                    self.close(code, &reason)?;
+                    self.peer_close = Some((code, reason));
+                    return Ok(None);
+                }
+                OpCode::Ping => {
+                    self.send_frame(OpCode::Pong, &payload)?;
+                }
+                OpCode::Pong => {
+                    self.last_pong = Some(Instant::now());
+                }
+            }
+        }
+    }
+
+    // Like recv, but returns Ok(None) straight away rather than waiting when
+    // no whole message has arrived yet; `is_open` tells that apart from the
+    // connection closing. A partly received message is kept for next time.
+    pub fn try_recv(&mut self) -> err::Result<Option<Payload>> {
+        // a tiny read timeout rather than a non-blocking socket, which would
+        // also affect writes on clones of it
+        self.client
+            .get_ref()
+            .set_read_timeout(Some(Duration::from_micros(1)))?;
+        self.polling = true;
+        let result = self.recv();
+        self.polling = false;
+        self.client.get_ref().set_read_timeout(self.keepalive)?;
+        match result {
+            Err(e) if e.is_timeout() => Ok(None),
+            r => r,
+        }
+    }
+
+    fn message(
+        &mut self,
+        opcode: OpCode,
+        compressed: bool,
+        mut data: Vec<u8>,
+    ) -> err::Result<Payload> {
+        if let (Some(inflater), true) = (&mut self.inflater, compressed) {
+            data.extend_from_slice(&[0, 0, 0xff, 0xff]);
+            data = match inflater.inflate(&data, self.max_message_size) {
+                Ok(d) => d,
+                Err(err::Error::TooLarge(_)) => return self.fail(1009, "message too big"),
+                Err(_) => return self.fail(1002, "bad compressed data"),
+            };
+        }
+        match opcode {
+            OpCode::Text => match String::from_utf8(data) {
+                Ok(s) => Ok(Payload::Str(s)),
+                Err(_) => self.fail(1007, "invalid utf-8 in text message"),
+            },
+            _ => Ok(Payload::Bin(data)),
+        }
+    }
+
+    fn next_header(&mut self) -> err::Result<Option<FrameHeader>> {
+        loop {
+            match FrameHeader::parse(&self.pending) {
+                Ok(Some(h)) => return Ok(Some(h)),
+                Ok(None) => {}
+                Err(err::Error::Input(msg)) => return self.fail(1002, &msg),
+                Err(e) => return Err(e),
+            }
+            if self.fill()? == 0 {
+                self.open = false;
+                return Ok(None);
+            }
+        }
+    }
 
-        self.client.consume(hdr.frame_len());
+    fn fill(&mut self) -> err::Result<usize> {
+        loop {
+            match self.client.fill_buf() {
+                Ok(buf) => {
+                    let n = buf.len();
+                    self.pending.extend_from_slice(buf);
+                    self.client.consume(n);
+                    self.ping_sent = false;
+                    return Ok(n);
+                }
+                Err(e)
+                    if self.keepalive.is_some()
+                        && !self.polling
+                        && matches!(
+                            e.kind(),
+                            std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                        ) =>
+                {
+                    if self.ping_sent {
+                        return self.fail(1011, "keepalive timed out");
+                    }
+                    self.send_frame(OpCode::Ping, b"")?;
+                    self.ping_sent = true;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
 
-        result
+    fn send_frame(&mut self, opcode: OpCode, payload: &[u8]) -> err::Result<usize> {
+        if let Some(writer) = &self.writer {
+            return writer.send_frame(opcode, payload);
+        }
+        write_frame(
+            self.client.get_mut(),
+            &mut self.scratch,
+            &mut self.compressor,
+            opcode,
+            payload,
+            self.send,
+        )
     }
 
     pub fn send_str(&mut self, msg: &str) -> err::Result<usize> {
-        let payload = msg.as_bytes();
-        let hdr = FrameHeader::final_text(payload.len(), None);
+        self.send_frame(OpCode::Text, msg.as_bytes())
+    }
+
+    pub fn send_bin(&mut self, msg: &[u8]) -> err::Result<usize> {
+        self.send_frame(OpCode::Binary, msg)
+    }
+
+    // Writes `bytes` to the peer exactly as given, with no framing, for
+    // tests that need malformed or hand-built frames.
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn send_raw(&mut self, bytes: &[u8]) -> err::Result<()> {
+        if let Some(writer) = &self.writer {
+            return writer.send_raw(bytes);
+        }
+        self.client.get_mut().write_all(bytes)?;
+        Ok(())
+    }
+
+    pub fn close(&mut self, code: u16, reason: &str) -> err::Result<()> {
+        if !self.open {
+            return Ok(());
+        }
+        self.open = false;
+        if let Some(writer) = &self.writer {
+            return writer.close(code, reason);
+        }
+
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(reason.as_bytes());
+        self.send_frame(OpCode::Close, &payload)?;
+        Ok(())
+    }
+
+    fn fail<T>(&mut self, code: u16, msg: &str) -> err::Result<T> {
+        // the peer may already be gone; the protocol error is what matters
+        let _ = self.close(code, msg);
+        match code {
+            1007 => Err(err::Error::Utf8(msg.to_string())),
+            _ => err::input(msg.to_string()),
+        }
+    }
+
+    pub fn set_max_message_size(&mut self, max: usize) {
+        self.max_message_size = max;
+    }
+
+    pub fn peer_close(&self) -> Option<(u16, &str)> {
+        self.peer_close
+            .as_ref()
+            .map(|(code, reason)| (*code, reason.as_str()))
+    }
+}
+
+// The codes a peer may put in a close frame (RFC 6455 7.4): the assigned
+// ones other than those kept for reporting locally, and the ranges left to
+// libraries and applications.
+fn close_code_allowed(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+}
+
+#[derive(Clone, Copy, Default)]
+struct SendOptions {
+    fragment_size: Option<usize>,
+    deflate: bool,
+}
+
+// Decompresses incoming permessage-deflate messages, keeping the tail of
+// earlier ones when the client compresses against them.
+struct Inflater {
+    context_takeover: bool,
+    history: Vec<u8>,
+}
+
+impl Inflater {
+    fn inflate(&mut self, data: &[u8], limit: usize) -> err::Result<Vec<u8>> {
+        let out = deflate::inflate_sync(data, &self.history, limit)?;
+        if self.context_takeover {
+            self.history.extend_from_slice(&out);
+            let excess = self.history.len().saturating_sub(32 * 1024);
+            self.history.drain(..excess);
+        }
+        Ok(out)
+    }
+}
+
+// Writes each frame's header and payload with a single call, reusing
+// `scratch` and the compressor's tables so a steady stream of small messages
+// doesn't allocate.
+fn write_frame(
+    out: &mut impl Write,
+    scratch: &mut Vec<u8>,
+    compressor: &mut Option<deflate::Compressor>,
+    opcode: OpCode,
+    payload: &[u8],
+    opts: SendOptions,
+) -> err::Result<usize> {
+    // compression is per message, and only kept when it actually helps
+    let compressed;
+    let (payload, mut rsv) = match opts.deflate && !opcode.is_control() {
+        true => {
+            compressed = compressor
+                .get_or_insert_with(deflate::Compressor::new)
+                .compress_sync(payload);
+            match &compressed[..compressed.len() - 4] {
+                c if c.len() < payload.len() => (c, RSV1),
+                _ => (payload, 0),
+            }
+        }
+        false => (payload, 0),
+    };
+
+    let size = match opts.fragment_size {
+        Some(n) if !opcode.is_control() => n.max(1),
+        _ => payload.len(),
+    };
+    let mut opcode = opcode;
+    let mut rest = payload;
+    let mut written = 0;
+    loop {
+        let (chunk, tail) = rest.split_at(size.min(rest.len()));
+        let mut hdr = FrameHeader::final_frame(opcode, chunk.len(), None);
+        hdr.fin = tail.is_empty();
+        hdr.rsv = rsv;
+        rsv = 0;
+        scratch.clear();
+        hdr.encode(scratch);
+        scratch.extend_from_slice(chunk);
+        out.write_all(scratch)?;
+        written += scratch.len();
+        if tail.is_empty() {
+            return Ok(written);
+        }
+        opcode = OpCode::Continuation;
+        rest = tail;
+    }
+}
+
+pub struct WsReader {
+    ws: WebSocket,
+}
+
+impl WsReader {
+    pub fn recv(&mut self) -> err::Result<Option<Payload>> {
+        self.ws.recv()
+    }
+
+    pub fn try_recv(&mut self) -> err::Result<Option<Payload>> {
+        self.ws.try_recv()
+    }
+
+    pub fn messages(&mut self) -> Messages<'_> {
+        self.ws.messages()
+    }
+
+    pub fn request(&self) -> &Req {
+        self.ws.request()
+    }
+
+    pub fn close(&mut self, code: u16, reason: &str) -> err::Result<()> {
+        self.ws.close(code, reason)
+    }
+
+    pub fn set_max_message_size(&mut self, max: usize) {
+        self.ws.set_max_message_size(max);
+    }
 
-        let out = self.client.get_mut();
-        let mut num = hdr.write(out)?;
-        num += out.write(payload)?;
-        Ok(num)
+    pub fn peer_close(&self) -> Option<(u16, &str)> {
+        self.ws.peer_close()
     }
+
+    pub fn is_open(&self) -> bool {
+        self.ws.is_open()
+    }
+}
+
+pub struct Messages<'a> {
+    ws: &'a mut WebSocket,
+    done: bool,
+}
+
+impl Iterator for Messages<'_> {
+    type Item = err::Result<Payload>;
+
+    fn next(&mut self) -> Option<err::Result<Payload>> {
+        if self.done {
+            return None;
+        }
+        let item = self.ws.recv().transpose();
+        self.done = !matches!(item, Some(Ok(_)));
+        item
+    }
+}
+
+struct WsOut {
+    client: Box<dyn Stream>,
+    scratch: Vec<u8>,
+    compressor: Option<deflate::Compressor>,
+    send: SendOptions,
+}
+
+#[derive(Clone)]
+pub struct WsWriter {
+    out: Arc<Mutex<WsOut>>,
+    closed: Arc<AtomicBool>,
+}
+
+impl WsWriter {
+    fn send_frame(&self, opcode: OpCode, payload: &[u8]) -> err::Result<usize> {
+        let mut out = self.out.lock().unwrap_or_else(|e| e.into_inner());
+        let WsOut {
+            client,
+            scratch,
+            compressor,
+            send,
+        } = &mut *out;
+        write_frame(client, scratch, compressor, opcode, payload, *send)
+    }
+
+    pub fn send_str(&self, msg: &str) -> err::Result<usize> {
+        self.send_frame(OpCode::Text, msg.as_bytes())
+    }
+
+    pub fn send_bin(&self, msg: &[u8]) -> err::Result<usize> {
+        self.send_frame(OpCode::Binary, msg)
+    }
+
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn send_raw(&self, bytes: &[u8]) -> err::Result<()> {
+        let mut out = self.out.lock().unwrap_or_else(|e| e.into_inner());
+        out.client.write_all(bytes)?;
+        Ok(())
+    }
+
+    pub fn set_fragment_size(&self, size: Option<usize>) {
+        let mut out = self.out.lock().unwrap_or_else(|e| e.into_inner());
+        out.send.fragment_size = size;
+    }
+
+    pub fn close(&self, code: u16, reason: &str) -> err::Result<()> {
+        if self.closed.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(reason.as_bytes());
+        self.send_frame(OpCode::Close, &payload)?;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FailureReason {
+    NotGet,
+    NotUpgrade,
+    NotWebSocket,
+    MissingKey,
+    NoProtocol,
 }
 
+#[allow(clippy::large_enum_variant)]
 pub enum WsUpgrade {
     Success(WebSocket),
-    Failure((Req, TcpStream)),
+    Failure((Req, Box<dyn Stream>), FailureReason),
     Error(err::Error),
 }
 
@@ -493,29 +3123,118 @@ impl From<std::io::Error> for WsUpgrade {
     }
 }
 
-pub fn ws_upgrade(req: Req, mut client: TcpStream) -> WsUpgrade {
-    match req.headers.get("Connection") {
-        Some(s) => match s.as_str() {
-            "Upgrade" => {}
-            _ => return WsUpgrade::Failure((req, client)),
-        },
-        None => return WsUpgrade::Failure((req, client)),
+#[derive(Default)]
+pub struct WsConfig {
+    pub protocols: Vec<String>,
+    pub require_protocol: bool,
+    pub permessage_deflate: bool,
+}
+
+fn select_protocol(req: &Req, supported: &[String]) -> Option<String> {
+    let offered: Vec<&str> = req
+        .header_all("Sec-WebSocket-Protocol")
+        .into_iter()
+        .flat_map(|v| v.split(','))
+        .map(|p| p.trim())
+        .collect();
+    supported
+        .iter()
+        .find(|p| offered.contains(&p.as_str()))
+        .cloned()
+}
+
+// Picks the first permessage-deflate offer we can honour, returning whether
+// the client promised not to compress against earlier messages. We never do
+// so ourselves, and our 32K window rules out smaller server_max_window_bits.
+fn select_deflate(req: &Req) -> Option<bool> {
+    let offers = req
+        .header_all("Sec-WebSocket-Extensions")
+        .into_iter()
+        .flat_map(|v| v.split(','));
+    'offers: for offer in offers {
+        let mut params = offer.split(';').map(|p| p.trim());
+        if params.next() != Some("permessage-deflate") {
+            continue;
+        }
+        let mut client_no_context_takeover = false;
+        for param in params {
+            let (name, value) = match param.split_once('=') {
+                Some((n, v)) => (n.trim(), Some(v.trim().trim_matches('"'))),
+                None => (param, None),
+            };
+            match (name, value) {
+                ("client_no_context_takeover", None) => client_no_context_takeover = true,
+                ("server_no_context_takeover", None) => {}
+                ("client_max_window_bits", _) => {}
+                ("server_max_window_bits", Some("15")) => {}
+                _ => continue 'offers,
+            }
+        }
+        return Some(client_no_context_takeover);
     }
+    None
+}
 
-    match req.headers.get("Upgrade") {
-        Some(s) => match s.as_str() {
-            "websocket" => {}
-            _ => return WsUpgrade::Failure((req, client)),
-        },
-        None => return WsUpgrade::Failure((req, client)),
+pub fn ws_upgrade(req: Req, client: Box<dyn Stream>) -> WsUpgrade {
+    ws_upgrade_with(req, client, &WsConfig::default())
+}
+
+pub fn ws_upgrade_with(req: Req, mut client: Box<dyn Stream>, config: &WsConfig) -> WsUpgrade {
+    if req.verb != Verb::Get {
+        return WsUpgrade::Failure((req, client), FailureReason::NotGet);
+    }
+
+    let connection = req.header("Connection").unwrap_or("");
+    if !connection
+        .split(',')
+        .any(|t| t.trim().eq_ignore_ascii_case("upgrade"))
+    {
+        return WsUpgrade::Failure((req, client), FailureReason::NotUpgrade);
+    }
+
+    let upgrade = req.header("Upgrade").unwrap_or("");
+    if !upgrade.eq_ignore_ascii_case("websocket") {
+        return WsUpgrade::Failure((req, client), FailureReason::NotWebSocket);
     }
 
-    let mut key = match req.headers.get("Sec-WebSocket-Key") {
+    let version = req.header("Sec-WebSocket-Version").unwrap_or("");
+    if version.trim() != "13" {
+        return bad_handshake(
+            &mut client,
+            Response::new(Status::BadRequest).header("Sec-WebSocket-Version", "13"),
+            format!("unsupported websocket version: {:?}", version),
+        );
+    }
+
+    let mut key = match req.header("Sec-WebSocket-Key") {
         Some(s) => s.to_string(),
-        None => {
-            return WsUpgrade::Error(err::Error::Input("missing Sec-WebSocket-Key".to_string()))
-        }
+        None => return WsUpgrade::Failure((req, client), FailureReason::MissingKey),
+    };
+    // the key is a base64 encoded 16 byte nonce
+    if !matches!(b64.decode(&key), Ok(k) if k.len() == 16) {
+        return bad_handshake(
+            &mut client,
+            Response::new(Status::BadRequest),
+            format!("bad websocket key: {:?}", key),
+        );
+    }
+
+    let protocol = select_protocol(&req, &config.protocols);
+    if protocol.is_none() && config.require_protocol {
+        return WsUpgrade::Failure((req, client), FailureReason::NoProtocol);
+    }
+
+    let deflate = match config.permessage_deflate {
+        true => select_deflate(&req),
+        false => None,
     };
+    let extensions = deflate.map(|client_no_context_takeover| {
+        let mut ext = "permessage-deflate; server_no_context_takeover".to_string();
+        if client_no_context_takeover {
+            ext.push_str("; client_no_context_takeover");
+        }
+        ext
+    });
 
     key.push_str("258EAFA5-E914-47DA-95CA-C5AB0DC85B11");
     let mut hash = Sha1::new();
@@ -523,21 +3242,55 @@ pub fn ws_upgrade(req: Req, mut client: TcpStream) -> WsUpgrade {
     let hash = hash.finalize();
     let accept = b64.encode(hash);
 
-    match write_ws_headers(&mut client, &accept) {
+    let headers = write_ws_headers(
+        &mut client,
+        &accept,
+        protocol.as_deref(),
+        extensions.as_deref(),
+    );
+    match headers {
         Ok(_) => {}
         Err(e) => return WsUpgrade::Error(e),
     }
 
-    WsUpgrade::Success(WebSocket::new(req, BufReader::new(client)))
+    let capacity = req.read_buffer;
+    let mut ws = WebSocket::new(req, BufReader::with_capacity(capacity, client));
+    ws.protocol = protocol;
+    if let Some(client_no_context_takeover) = deflate {
+        ws.send.deflate = true;
+        ws.inflater = Some(Inflater {
+            context_takeover: !client_no_context_takeover,
+            history: Vec::new(),
+        });
+    }
+    WsUpgrade::Success(ws)
+}
+
+fn bad_handshake(client: &mut Box<dyn Stream>, resp: Response, msg: String) -> WsUpgrade {
+    match resp.write_to(client) {
+        Ok(_) => WsUpgrade::Error(err::Error::Input(msg)),
+        Err(e) => WsUpgrade::Error(e),
+    }
 }
 
-fn write_ws_headers(client: &mut TcpStream, accept: &str) -> err::Result<()> {
-    write!(client, "HTTP/1.0 {}\n", Status::SwitchingProtocols)?;
-    write!(client, "Server: webd 0.1\n")?;
-    write!(client, "Connection: upgrade\n")?;
-    write!(client, "Upgrade: websocket\n")?;
-    write!(client, "Sec-WebSocket-Accept: {}\n", accept)?;
-    write!(client, "\n")?;
+fn write_ws_headers(
+    client: &mut impl Write,
+    accept: &str,
+    protocol: Option<&str>,
+    extensions: Option<&str>,
+) -> err::Result<()> {
+    write!(client, "HTTP/1.1 {}\r\n", Status::SwitchingProtocols)?;
+    write!(client, "Date: {}\r\n", date::now())?;
+    write!(client, "Connection: upgrade\r\n")?;
+    write!(client, "Upgrade: websocket\r\n")?;
+    write!(client, "Sec-WebSocket-Accept: {}\r\n", accept)?;
+    if let Some(protocol) = protocol {
+        write!(client, "Sec-WebSocket-Protocol: {}\r\n", protocol)?;
+    }
+    if let Some(extensions) = extensions {
+        write!(client, "Sec-WebSocket-Extensions: {}\r\n", extensions)?;
+    }
+    write!(client, "\r\n")?;
 
     Ok(())
 }
@@ -0,0 +1,139 @@
+use crate::{err, Req};
+
+pub struct Part {
+    pub headers: Vec<(String, String)>,
+    pub name: Option<String>,
+    pub filename: Option<String>,
+    pub body: Vec<u8>,
+}
+
+impl Part {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    haystack
+        .get(from..)?
+        .windows(needle.len())
+        .position(|w| w == needle)
+        .map(|i| i + from)
+}
+
+// Splits `a; b="c; d"; e=f` on the semicolons that are not inside quotes.
+pub(crate) fn params(value: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    let mut quoted = false;
+    let mut start = 0;
+    for (i, c) in value
+        .char_indices()
+        .chain(std::iter::once((value.len(), ';')))
+    {
+        match c {
+            '"' => quoted = !quoted,
+            ';' if !quoted => {
+                if let Some((k, v)) = value[start..i].split_once('=') {
+                    let v = v.trim();
+                    let v = v
+                        .strip_prefix('"')
+                        .and_then(|v| v.strip_suffix('"'))
+                        .unwrap_or(v);
+                    params.push((k.trim().to_ascii_lowercase(), v.to_string()));
+                }
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    params
+}
+
+fn param(value: &str, name: &str) -> Option<String> {
+    params(value)
+        .into_iter()
+        .find(|(k, _)| k == name)
+        .map(|(_, v)| v)
+}
+
+fn parse_part(raw: &[u8]) -> err::Result<Part> {
+    let (head, body) = if raw.starts_with(b"\r\n") {
+        (&raw[..0], &raw[2..])
+    } else {
+        match find(raw, b"\r\n\r\n", 0) {
+            Some(i) => (&raw[..i], &raw[i + 4..]),
+            None => return err::input("multipart part without headers end".to_string()),
+        }
+    };
+
+    let mut headers = Vec::new();
+    for line in String::from_utf8_lossy(head).split("\r\n") {
+        match line.split_once(':') {
+            Some((n, v)) => headers.push((n.trim().to_string(), v.trim().to_string())),
+            None => return err::input(format!("bad multipart header: {}", line)),
+        }
+    }
+
+    let disposition = headers
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case("Content-Disposition"))
+        .map_or("", |(_, v)| v.as_str());
+    Ok(Part {
+        name: param(disposition, "name"),
+        filename: param(disposition, "filename"),
+        headers,
+        body: body.to_vec(),
+    })
+}
+
+pub fn parse(req: &Req) -> err::Result<Vec<Part>> {
+    let media = match req.content_type() {
+        Some(m) if m.essence == "multipart/form-data" => m,
+        _ => {
+            let content_type = req.header("Content-Type").unwrap_or("");
+            return err::input(format!("not a multipart body: {}", content_type));
+        }
+    };
+    let boundary = match media.param("boundary") {
+        Some(b) if !b.is_empty() => b,
+        _ => return err::input("multipart body without boundary".to_string()),
+    };
+
+    let body = &req.body;
+    let delim = format!("--{}", boundary).into_bytes();
+    let next = format!("\r\n--{}", boundary).into_bytes();
+
+    let mut pos = if body.starts_with(&delim) {
+        0
+    } else {
+        match find(body, &next, 0) {
+            Some(i) => i + 2,
+            None => return err::input("multipart boundary not found".to_string()),
+        }
+    };
+
+    let mut parts = Vec::new();
+    loop {
+        pos += delim.len();
+        if body[pos..].starts_with(b"--") {
+            return Ok(parts);
+        }
+        while matches!(body.get(pos), Some(b' ' | b'\t')) {
+            pos += 1;
+        }
+        if !body[pos..].starts_with(b"\r\n") {
+            return err::input("multipart boundary not followed by CRLF".to_string());
+        }
+        pos += 2;
+
+        let end = match find(body, &next, pos) {
+            Some(i) => i,
+            None => return err::input("multipart body missing closing boundary".to_string()),
+        };
+        parts.push(parse_part(&body[pos..end])?);
+        pos = end + 2;
+    }
+}
@@ -0,0 +1,93 @@
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+type Job = Box<dyn FnOnce() + Send>;
+type Busy = Arc<(Mutex<usize>, Condvar)>;
+
+pub struct ThreadPool {
+    jobs: Option<Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+    busy: Busy,
+}
+
+impl ThreadPool {
+    pub fn new(size: usize) -> ThreadPool {
+        let (tx, rx) = channel::<Job>();
+        let rx = Arc::new(Mutex::new(rx));
+        let busy: Busy = Arc::new((Mutex::new(0), Condvar::new()));
+        let workers = (0..size.max(1))
+            .map(|_| {
+                let rx = rx.clone();
+                let busy = busy.clone();
+                thread::spawn(move || work(rx, busy))
+            })
+            .collect();
+
+        ThreadPool {
+            jobs: Some(tx),
+            workers,
+            busy,
+        }
+    }
+
+    pub fn execute(&self, job: impl FnOnce() + Send + 'static) {
+        if let Some(jobs) = &self.jobs {
+            *lock(&self.busy.0) += 1;
+            // a send only fails once the pool is being dropped
+            if jobs.send(Box::new(job)).is_err() {
+                *lock(&self.busy.0) -= 1;
+            }
+        }
+    }
+
+    // Waits for every queued and running job to finish, giving up after
+    // `timeout`. Returns whether the pool went idle.
+    pub fn wait_idle(&self, timeout: Duration) -> bool {
+        let (count, idle) = &*self.busy;
+        match idle.wait_timeout_while(lock(count), timeout, |n| *n > 0) {
+            Ok((n, _)) => *n == 0,
+            Err(_) => false,
+        }
+    }
+
+    // Drops the pool without waiting for the workers, which exit once their
+    // current jobs end.
+    pub fn detach(mut self) {
+        self.workers.clear();
+    }
+}
+
+fn lock(count: &Mutex<usize>) -> std::sync::MutexGuard<'_, usize> {
+    count.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn work(rx: Arc<Mutex<Receiver<Job>>>, busy: Busy) {
+    loop {
+        let job = match rx.lock() {
+            Ok(rx) => rx.recv(),
+            Err(_) => return,
+        };
+        match job {
+            Ok(job) => {
+                // keep the worker alive; the job has logged what it could
+                let _ = catch_unwind(AssertUnwindSafe(job));
+                let (count, idle) = &*busy;
+                *lock(count) -= 1;
+                idle.notify_all();
+            }
+            Err(_) => return,
+        }
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        self.jobs.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
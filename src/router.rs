@@ -0,0 +1,183 @@
+use crate::cors::Cors;
+use crate::{err, App, Req, Response, Status, Stream, Verb};
+use std::collections::HashMap;
+
+enum Segment {
+    Static(String),
+    Param(String),
+}
+
+struct Route {
+    verb: Verb,
+    segments: Vec<Segment>,
+    handler: Box<dyn App>,
+}
+
+impl Route {
+    fn matches(&self, path: &[&str]) -> Option<HashMap<String, String>> {
+        if path.len() != self.segments.len() {
+            return None;
+        }
+        let mut params = HashMap::new();
+        for (seg, part) in self.segments.iter().zip(path) {
+            match seg {
+                Segment::Static(s) if s == part => {}
+                Segment::Param(name) if !part.is_empty() => {
+                    params.insert(name.clone(), part.to_string());
+                }
+                _ => return None,
+            }
+        }
+        Some(params)
+    }
+
+    fn specificity(&self) -> Vec<bool> {
+        self.segments
+            .iter()
+            .map(|s| matches!(s, Segment::Static(_)))
+            .collect()
+    }
+}
+
+fn send_allow(
+    mut client: Box<dyn Stream>,
+    status: Status,
+    verbs: &[Verb],
+    body: &str,
+) -> err::Result<()> {
+    let mut verbs = verbs.to_vec();
+    // HEAD is served by the GET route, and OPTIONS by the router itself
+    if verbs.contains(&Verb::Get) && !verbs.contains(&Verb::Head) {
+        verbs.push(Verb::Head);
+    }
+    if !verbs.contains(&Verb::Options) {
+        verbs.push(Verb::Options);
+    }
+    let verbs: Vec<_> = verbs.iter().map(|v| v.to_string()).collect();
+    let mut resp = Response::new(status).header("Allow", &verbs.join(", "));
+    if !body.is_empty() {
+        resp = resp.content_type("text/plain").body_str(body);
+    }
+    resp.write_to(&mut client)
+}
+
+fn split_path(path: &str) -> Vec<&str> {
+    path.trim_start_matches('/').split('/').collect()
+}
+
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+    cors: Option<Cors>,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router::default()
+    }
+
+    pub fn route(&mut self, verb: Verb, path: &str, handler: impl App + 'static) -> &mut Router {
+        let segments = split_path(path)
+            .into_iter()
+            .map(|s| match s.strip_prefix(':') {
+                Some(name) => Segment::Param(name.to_string()),
+                None => Segment::Static(s.to_string()),
+            })
+            .collect();
+        self.routes.push(Route {
+            verb,
+            segments,
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    pub fn get(&mut self, path: &str, handler: impl App + 'static) -> &mut Router {
+        self.route(Verb::Get, path, handler)
+    }
+
+    pub fn post(&mut self, path: &str, handler: impl App + 'static) -> &mut Router {
+        self.route(Verb::Post, path, handler)
+    }
+
+    pub fn put(&mut self, path: &str, handler: impl App + 'static) -> &mut Router {
+        self.route(Verb::Put, path, handler)
+    }
+
+    pub fn delete(&mut self, path: &str, handler: impl App + 'static) -> &mut Router {
+        self.route(Verb::Delete, path, handler)
+    }
+
+    pub fn patch(&mut self, path: &str, handler: impl App + 'static) -> &mut Router {
+        self.route(Verb::Patch, path, handler)
+    }
+
+    pub fn cors(&mut self, cors: Cors) -> &mut Router {
+        self.cors = Some(cors);
+        self
+    }
+
+    pub fn handle(&self, mut req: Req, mut client: Box<dyn Stream>) -> err::Result<()> {
+        if let Some(cors) = &self.cors {
+            if cors.is_preflight(&req) {
+                return cors.preflight(&req, client);
+            }
+            for (name, value) in cors.headers(&req) {
+                client.add_header(&name, &value);
+            }
+        }
+
+        if req.verb == Verb::Options && req.path == "*" {
+            let mut allowed = Vec::new();
+            for route in &self.routes {
+                if !allowed.contains(&route.verb) {
+                    allowed.push(route.verb);
+                }
+            }
+            return send_allow(client, Status::NoContent, &allowed, "");
+        }
+
+        let path = split_path(&req.path);
+        let mut allowed = Vec::new();
+        let mut best: Option<(&Route, HashMap<String, String>)> = None;
+        for route in &self.routes {
+            let params = match route.matches(&path) {
+                Some(p) => p,
+                None => continue,
+            };
+            if !allowed.contains(&route.verb) {
+                allowed.push(route.verb);
+            }
+            let head_as_get = req.verb == Verb::Head && route.verb == Verb::Get;
+            if route.verb != req.verb && !head_as_get {
+                continue;
+            }
+            match &best {
+                Some((b, _)) if b.specificity() >= route.specificity() => {}
+                _ => best = Some((route, params)),
+            }
+        }
+
+        match best {
+            Some((route, params)) => {
+                req.params = params;
+                (route.handler)(req, client)
+            }
+            // left unanswered, so the server sends its not-found page
+            None if allowed.is_empty() => Ok(()),
+            None if req.verb == Verb::Options => {
+                send_allow(client, Status::NoContent, &allowed, "")
+            }
+            None => send_allow(
+                client,
+                Status::MethodNotAllowed,
+                &allowed,
+                "method not allowed\n",
+            ),
+        }
+    }
+
+    pub fn into_app(self) -> impl App {
+        move |req, client| self.handle(req, client)
+    }
+}
@@ -0,0 +1,57 @@
+use crate::{err, handle_client, App, Config, Stream};
+use std::io::{Cursor, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// A connection held in memory: reads come from the raw request bytes and
+// everything written is kept for the caller to inspect.
+#[derive(Clone)]
+struct Conn {
+    input: Arc<Mutex<Cursor<Vec<u8>>>>,
+    output: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Read for Conn {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.input.lock().unwrap().read(buf)
+    }
+}
+
+impl Write for Conn {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.output.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Stream for Conn {
+    fn try_clone(&self) -> std::io::Result<Box<dyn Stream>> {
+        Ok(Box::new(self.clone()))
+    }
+
+    // reads never wait, so any timeout is met
+    fn set_read_timeout(&self, _timeout: Option<Duration>) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn shutdown(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+pub fn request(app: &impl App, raw: &[u8]) -> err::Result<Vec<u8>> {
+    request_with(&Config::default(), app, raw)
+}
+
+pub fn request_with(config: &Config, app: &impl App, raw: &[u8]) -> err::Result<Vec<u8>> {
+    let conn = Conn {
+        input: Arc::new(Mutex::new(Cursor::new(raw.to_vec()))),
+        output: Arc::new(Mutex::new(Vec::new())),
+    };
+    handle_client(conn.clone(), config, app)?;
+    let output = conn.output.lock().unwrap().clone();
+    Ok(output)
+}
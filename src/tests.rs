@@ -1,5 +1,27 @@
 use crate::err;
-use std::io::BufReader;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+
+fn socket_pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (server, _) = listener.accept().unwrap();
+    (client, server)
+}
+
+fn read_head<T: BufRead>(mut stream: T) -> Vec<String> {
+    let mut lines = Vec::new();
+    loop {
+        let mut line = String::new();
+        stream.read_line(&mut line).unwrap();
+        let line = line.trim_end().to_string();
+        if line.is_empty() {
+            return lines;
+        }
+        lines.push(line);
+    }
+}
 
 #[test]
 fn parse_req() -> err::Result<()> {
@@ -7,10 +29,3670 @@ fn parse_req() -> err::Result<()> {
     raw.push_str("GET /api/map HTTP/1.1\n");
     raw.push_str("Host: example.com\n");
     raw.push_str("Accept: */*\n");
-    raw.push_str("\n");
+    raw.push('\n');
 
     let req = crate::Req::parse(BufReader::new(raw.as_bytes()))?;
     assert_eq!(req.path.as_str(), "/api/map");
 
     Ok(())
 }
+
+#[test]
+fn parse_post_body() -> err::Result<()> {
+    let mut raw = String::new();
+    raw.push_str("POST /submit HTTP/1.1\n");
+    raw.push_str("Host: example.com\n");
+    raw.push_str("Content-Length: 11\n");
+    raw.push('\n');
+    raw.push_str("hello world");
+
+    let req = crate::Req::parse(BufReader::new(raw.as_bytes()))?;
+    assert_eq!(req.verb.to_string(), "POST");
+    assert_eq!(req.body, b"hello world");
+
+    Ok(())
+}
+
+#[test]
+fn parse_get_has_empty_body() -> err::Result<()> {
+    let mut raw = String::new();
+    raw.push_str("GET / HTTP/1.1\n");
+    raw.push_str("Host: example.com\n");
+    raw.push('\n');
+
+    let req = crate::Req::parse(BufReader::new(raw.as_bytes()))?;
+    assert!(req.body.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn verb_round_trip() {
+    let verbs = [
+        "GET", "POST", "PUT", "DELETE", "PATCH", "HEAD", "OPTIONS", "TRACE",
+    ];
+    for v in verbs {
+        let verb = crate::Verb::parse(v).expect(v);
+        assert_eq!(verb.to_string(), v);
+    }
+}
+
+#[test]
+fn parse_unknown_verb() {
+    let raw = "BREW /pot HTTP/1.1\nHost: localhost\n\n";
+    match crate::Req::parse(BufReader::new(raw.as_bytes())) {
+        Err(err::Error::Input(msg)) => assert_eq!(msg, "unknown verb: BREW"),
+        _ => panic!("expected an input error"),
+    }
+}
+
+#[test]
+fn parse_query_string() -> err::Result<()> {
+    let raw = "GET /search?q=rust%20lang&page=2&q2=a+b HTTP/1.1\nHost: localhost\n\n";
+
+    let req = crate::Req::parse(BufReader::new(raw.as_bytes()))?;
+    assert_eq!(req.path.as_str(), "/search");
+    assert_eq!(req.query_param("q"), Some("rust lang"));
+    assert_eq!(req.query_param("page"), Some("2"));
+    assert_eq!(req.query_param("q2"), Some("a b"));
+    assert_eq!(req.query_param("missing"), None);
+
+    Ok(())
+}
+
+#[test]
+fn parse_encoded_path() -> err::Result<()> {
+    let raw = "GET /a%2Fb%20c HTTP/1.1\nHost: localhost\n\n";
+
+    let req = crate::Req::parse(BufReader::new(raw.as_bytes()))?;
+    assert_eq!(req.path.as_str(), "/a/b c");
+
+    Ok(())
+}
+
+#[test]
+fn parse_bad_path_escapes() {
+    for path in ["/%zz", "/trailing%", "/short%4", "/%ff%fe"] {
+        let raw = format!("GET {} HTTP/1.1\nHost: localhost\n\n", path);
+        match crate::Req::parse(BufReader::new(raw.as_bytes())) {
+            Err(err::Error::Input(_)) => {}
+            _ => panic!("expected an input error for {}", path),
+        }
+    }
+}
+
+#[test]
+fn parse_header_with_colons() -> err::Result<()> {
+    let mut raw = String::new();
+    raw.push_str("GET / HTTP/1.1\n");
+    raw.push_str("Host: example.com:8080\n");
+    raw.push_str("X-Url: http://a.com:9000/x\n");
+    raw.push('\n');
+
+    let req = crate::Req::parse(BufReader::new(raw.as_bytes()))?;
+    assert_eq!(req.header("Host"), Some("example.com:8080"));
+    assert_eq!(req.header("X-Url"), Some("http://a.com:9000/x"));
+
+    Ok(())
+}
+
+#[test]
+fn header_lookup_ignores_case() -> err::Result<()> {
+    let raw = "GET / HTTP/1.1\nHost: localhost\nCONTENT-type: text/plain\n\n";
+
+    let req = crate::Req::parse(BufReader::new(raw.as_bytes()))?;
+    assert_eq!(req.header("Content-Type"), Some("text/plain"));
+    assert_eq!(req.header("content-type"), Some("text/plain"));
+    assert!(req.headers.contains_key("content-type"));
+
+    Ok(())
+}
+
+#[test]
+fn ws_upgrade_lowercase_headers() -> err::Result<()> {
+    let mut raw = String::new();
+    raw.push_str("GET /chat HTTP/1.1\nHost: localhost\n");
+    raw.push_str("upgrade: websocket\n");
+    raw.push_str("connection: keep-alive, upgrade\n");
+    raw.push_str("sec-websocket-key: dGhlIHNhbXBsZSBub25jZQ==\n");
+    raw.push_str("sec-websocket-version: 13\n");
+    raw.push('\n');
+
+    let req = crate::Req::parse(BufReader::new(raw.as_bytes()))?;
+    let (client, server) = socket_pair();
+    match crate::ws_upgrade(req, Box::new(server)) {
+        crate::WsUpgrade::Success(_) => {}
+        _ => panic!("expected the upgrade to succeed"),
+    }
+
+    let head = read_head(BufReader::new(client));
+    assert_eq!(head[0], "HTTP/1.1 101 Switching Protocols");
+    assert!(head
+        .iter()
+        .any(|h| h == "Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+
+    Ok(())
+}
+
+#[test]
+fn ws_upgrade_selects_protocol() -> err::Result<()> {
+    let raw = "GET /chat HTTP/1.1\nHost: localhost\n\
+               Upgrade: websocket\n\
+               Connection: Upgrade\n\
+               Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\n\
+               Sec-WebSocket-Version: 13\n\
+               Sec-WebSocket-Protocol: chat, superchat\n\n";
+    let config = crate::WsConfig {
+        protocols: vec![
+            "mqtt".to_string(),
+            "superchat".to_string(),
+            "chat".to_string(),
+        ],
+        ..Default::default()
+    };
+
+    let req = crate::Req::parse(BufReader::new(raw.as_bytes()))?;
+    let (client, server) = socket_pair();
+    let ws = match crate::ws_upgrade_with(req, Box::new(server), &config) {
+        crate::WsUpgrade::Success(ws) => ws,
+        _ => panic!("expected the upgrade to succeed"),
+    };
+    assert_eq!(ws.protocol(), Some("superchat"));
+    let head = read_head(BufReader::new(client));
+    assert_eq!(
+        header_value(&head, "Sec-WebSocket-Protocol"),
+        Some("superchat")
+    );
+
+    let config = crate::WsConfig {
+        protocols: vec!["chat".to_string()],
+        ..Default::default()
+    };
+    let req = crate::Req::parse(BufReader::new(raw.as_bytes()))?;
+    let (client, server) = socket_pair();
+    match crate::ws_upgrade_with(req, Box::new(server), &config) {
+        crate::WsUpgrade::Success(ws) => assert_eq!(ws.protocol(), Some("chat")),
+        _ => panic!("expected the upgrade to succeed"),
+    }
+    let head = read_head(BufReader::new(client));
+    assert_eq!(header_value(&head, "Sec-WebSocket-Protocol"), Some("chat"));
+
+    let config = crate::WsConfig {
+        protocols: vec!["mqtt".to_string()],
+        require_protocol: true,
+        ..Default::default()
+    };
+    let req = crate::Req::parse(BufReader::new(raw.as_bytes()))?;
+    let (_client, server) = socket_pair();
+    match crate::ws_upgrade_with(req, Box::new(server), &config) {
+        crate::WsUpgrade::Failure(_, crate::FailureReason::NoProtocol) => {}
+        _ => panic!("expected the upgrade to be refused"),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn ws_upgrade_rejects_old_version() -> err::Result<()> {
+    let raw = "GET /chat HTTP/1.1\nHost: localhost\n\
+               Upgrade: websocket\n\
+               Connection: Upgrade\n\
+               Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\n\
+               Sec-WebSocket-Version: 8\n\n";
+    let req = crate::Req::parse(BufReader::new(raw.as_bytes()))?;
+    let (client, server) = socket_pair();
+    match crate::ws_upgrade(req, Box::new(server)) {
+        crate::WsUpgrade::Error(err::Error::Input(_)) => {}
+        _ => panic!("expected the upgrade to be rejected"),
+    }
+
+    let head = read_head(BufReader::new(client));
+    assert_eq!(head[0], "HTTP/1.1 400 Bad Request");
+    assert_eq!(header_value(&head, "Sec-WebSocket-Version"), Some("13"));
+    assert_eq!(header_value(&head, "Sec-WebSocket-Accept"), None);
+
+    Ok(())
+}
+
+#[test]
+fn ws_upgrade_failure_reasons() -> err::Result<()> {
+    let cases = [
+        ("", crate::FailureReason::NotUpgrade),
+        (
+            "Connection: Upgrade\nUpgrade: h2c\n",
+            crate::FailureReason::NotWebSocket,
+        ),
+        (
+            "Connection: Upgrade\nUpgrade: websocket\nSec-WebSocket-Version: 13\n",
+            crate::FailureReason::MissingKey,
+        ),
+    ];
+    for (headers, expected) in cases {
+        let raw = format!("GET /chat HTTP/1.1\nHost: localhost\n{}\n", headers);
+        let req = crate::Req::parse(BufReader::new(raw.as_bytes()))?;
+        let (_client, server) = socket_pair();
+        match crate::ws_upgrade(req, Box::new(server)) {
+            crate::WsUpgrade::Failure((req, _), reason) => {
+                assert_eq!(reason, expected);
+                assert_eq!(req.path, "/chat");
+            }
+            _ => panic!("expected the upgrade to fail"),
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn ws_upgrade_rejects_bad_key() -> err::Result<()> {
+    for key in ["dGhlIHNhbXBsZQ==", "", "not base64 at all!"] {
+        let raw = format!(
+            "GET /chat HTTP/1.1\nHost: localhost\n\
+             Upgrade: websocket\n\
+             Connection: Upgrade\n\
+             Sec-WebSocket-Key: {}\n\
+             Sec-WebSocket-Version: 13\n\n",
+            key
+        );
+        let req = crate::Req::parse(BufReader::new(raw.as_bytes()))?;
+        let (client, server) = socket_pair();
+        match crate::ws_upgrade(req, Box::new(server)) {
+            crate::WsUpgrade::Error(err::Error::Input(_)) => {}
+            _ => panic!("expected the key {:?} to be rejected", key),
+        }
+
+        let head = read_head(BufReader::new(client));
+        assert_eq!(head[0], "HTTP/1.1 400 Bad Request");
+        assert_eq!(header_value(&head, "Sec-WebSocket-Accept"), None);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn ws_upgrade_requires_get() -> err::Result<()> {
+    let raw = "POST /chat HTTP/1.1\nHost: localhost\n\
+               Upgrade: websocket\n\
+               Connection: Upgrade\n\
+               Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\n\
+               Sec-WebSocket-Version: 13\n\n";
+    let req = crate::Req::parse(BufReader::new(raw.as_bytes()))?;
+    let (mut client, server) = socket_pair();
+    match crate::ws_upgrade(req, Box::new(server)) {
+        crate::WsUpgrade::Failure((req, _), crate::FailureReason::NotGet) => {
+            assert_eq!(req.verb, crate::Verb::Post);
+        }
+        _ => panic!("expected the upgrade to fail"),
+    }
+
+    // nothing was sent back
+    let mut resp = Vec::new();
+    client.read_to_end(&mut resp)?;
+    assert!(resp.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn parse_repeated_headers() -> err::Result<()> {
+    let mut raw = String::new();
+    raw.push_str("GET / HTTP/1.1\nHost: localhost\n");
+    raw.push_str("X-Test: one\n");
+    raw.push_str("x-test: two\n");
+    raw.push('\n');
+
+    let req = crate::Req::parse(BufReader::new(raw.as_bytes()))?;
+    assert_eq!(req.header("X-Test"), Some("one"));
+    assert_eq!(req.header_all("X-Test"), vec!["one", "two"]);
+    assert!(req.header_all("X-Other").is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn parse_header_flood() {
+    let mut raw = String::new();
+    raw.push_str("GET / HTTP/1.1\nHost: localhost\n");
+    for i in 0..10_000 {
+        raw.push_str(&format!("X-Flood-{}: {}\n", i, i));
+    }
+    raw.push('\n');
+
+    match crate::Req::parse(BufReader::new(raw.as_bytes())) {
+        Err(err::Error::Input(msg)) => assert_eq!(msg, "more than 100 headers"),
+        _ => panic!("expected an input error"),
+    }
+}
+
+#[test]
+fn parse_long_request_line() {
+    let raw = format!(
+        "GET /{} HTTP/1.1\nHost: localhost\n\n",
+        "a".repeat(1024 * 1024)
+    );
+
+    match crate::Req::parse(BufReader::new(raw.as_bytes())) {
+        Err(err::Error::Input(msg)) => assert_eq!(msg, "line longer than 8192 bytes"),
+        _ => panic!("expected an input error"),
+    }
+}
+
+#[test]
+fn parse_with_custom_limits() {
+    let mut raw = String::new();
+    raw.push_str("GET / HTTP/1.1\nHost: localhost\n");
+    raw.push_str("X-Big: ");
+    raw.push_str(&"b".repeat(200));
+    raw.push_str("\n\n");
+
+    let limits = crate::Limits {
+        max_header_bytes: 128,
+        ..Default::default()
+    };
+    match crate::Req::parse_with_limits(BufReader::new(raw.as_bytes()), &limits) {
+        Err(err::Error::Input(msg)) => assert_eq!(msg, "headers longer than 128 bytes"),
+        _ => panic!("expected an input error"),
+    }
+}
+
+#[test]
+fn send_file_missing_is_not_found() {
+    let (_client, server) = socket_pair();
+    match crate::send_file(server, crate::Status::OK, "text/plain", "/no/such/file") {
+        Err(e) => assert!(e.is_not_found()),
+        Ok(_) => panic!("expected an error"),
+    }
+}
+
+const MASK: [u8; 4] = [0x37, 0xfa, 0x21, 0x3d];
+
+fn client_frame(first: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![first];
+    let n = payload.len();
+    if n > u16::MAX as usize {
+        frame.push(0x80 | 127);
+        frame.extend_from_slice(&(n as u64).to_be_bytes());
+    } else if n > 125 {
+        frame.push(0x80 | 126);
+        frame.extend_from_slice(&(n as u16).to_be_bytes());
+    } else {
+        frame.push(0x80 | n as u8);
+    }
+    frame.extend_from_slice(&MASK);
+    frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ MASK[i % 4]));
+    frame
+}
+
+fn ws_pair() -> (TcpStream, crate::WebSocket) {
+    let req = crate::Req::parse(BufReader::new(
+        "GET /ws HTTP/1.1\nHost: localhost\n\n".as_bytes(),
+    ))
+    .unwrap();
+    let (client, server) = socket_pair();
+    (
+        client,
+        crate::WebSocket::new(req, BufReader::new(Box::new(server))),
+    )
+}
+
+#[test]
+fn ws_ping_gets_pong() -> err::Result<()> {
+    let (mut client, mut ws) = ws_pair();
+
+    let mut frames = client_frame(0x89, b"hi");
+    frames.extend(client_frame(0x81, b"next"));
+    client.write_all(&frames)?;
+
+    match ws.recv()? {
+        Some(crate::Payload::Str(s)) => assert_eq!(s, "next"),
+        p => panic!("unexpected payload: {:?}", p),
+    }
+
+    let mut pong = [0u8; 4];
+    client.read_exact(&mut pong)?;
+    assert_eq!(pong, [0x8a, 0x02, b'h', b'i']);
+
+    Ok(())
+}
+
+#[test]
+fn ws_pong_is_skipped() -> err::Result<()> {
+    let (mut client, mut ws) = ws_pair();
+
+    let mut frames = client_frame(0x8a, b"");
+    frames.extend(client_frame(0x81, b"after pong"));
+    client.write_all(&frames)?;
+
+    match ws.recv()? {
+        Some(crate::Payload::Str(s)) => assert_eq!(s, "after pong"),
+        p => panic!("unexpected payload: {:?}", p),
+    }
+    assert!(ws.last_pong.is_some());
+
+    Ok(())
+}
+
+#[test]
+fn ws_reassembles_fragments() -> err::Result<()> {
+    let (mut client, mut ws) = ws_pair();
+
+    let mut frames = client_frame(0x01, b"hello");
+    frames.extend(client_frame(0x89, b"mid"));
+    frames.extend(client_frame(0x00, b" "));
+    frames.extend(client_frame(0x80, b"world"));
+    client.write_all(&frames)?;
+
+    match ws.recv()? {
+        Some(crate::Payload::Str(s)) => assert_eq!(s, "hello world"),
+        p => panic!("unexpected payload: {:?}", p),
+    }
+
+    let mut pong = [0u8; 5];
+    client.read_exact(&mut pong)?;
+    assert_eq!(pong, [0x8a, 0x03, b'm', b'i', b'd']);
+
+    Ok(())
+}
+
+#[test]
+fn ws_close_handshake() -> err::Result<()> {
+    let (mut client, mut ws) = ws_pair();
+
+    let mut payload = 1001u16.to_be_bytes().to_vec();
+    payload.extend_from_slice(b"bye");
+    client.write_all(&client_frame(0x88, &payload))?;
+
+    assert!(ws.recv()?.is_none());
+    assert_eq!(ws.peer_close(), Some((1001, "bye")));
+
+    let mut close = [0u8; 7];
+    client.read_exact(&mut close)?;
+    assert_eq!(close, [0x88, 0x05, 0x03, 0xe9, b'b', b'y', b'e']);
+
+    Ok(())
+}
+
+#[test]
+fn ws_close_without_code() -> err::Result<()> {
+    let (mut client, mut ws) = ws_pair();
+
+    client.write_all(&client_frame(0x88, b""))?;
+
+    assert!(ws.recv()?.is_none());
+    assert_eq!(ws.peer_close(), Some((1000, "")));
+
+    let mut close = [0u8; 4];
+    client.read_exact(&mut close)?;
+    assert_eq!(close, [0x88, 0x02, 0x03, 0xe8]);
+
+    Ok(())
+}
+
+#[test]
+fn ws_rejects_bad_close_payload() -> err::Result<()> {
+    assert_ws_protocol_error(&client_frame(0x88, &[0x03]), "one byte close payload")?;
+    for code in [0u16, 999, 1004, 1005, 1006, 1015, 1016, 2999, 5000] {
+        assert_ws_protocol_error(
+            &client_frame(0x88, &code.to_be_bytes()),
+            &format!("bad close code {}", code),
+        )?;
+    }
+
+    // a reason that isn't UTF-8 fails the connection as text would
+    let (mut client, mut ws) = ws_pair();
+    client.write_all(&client_frame(0x88, b"\x03\xe8bye \xff"))?;
+    match ws.recv() {
+        Err(err::Error::Utf8(msg)) => assert_eq!(msg, "invalid utf-8 in close reason"),
+        p => panic!("expected a utf-8 error, got {:?}", p.map(|_| ())),
+    }
+    let mut close = [0u8; 4];
+    client.read_exact(&mut close)?;
+    assert_eq!(close[2..], [0x03, 0xef]);
+    assert_eq!(ws.peer_close(), None);
+
+    // codes left to applications are fine
+    let (mut client, mut ws) = ws_pair();
+    client.write_all(&client_frame(0x88, &4000u16.to_be_bytes()))?;
+    assert!(ws.recv()?.is_none());
+    assert_eq!(ws.peer_close(), Some((4000, "")));
+
+    Ok(())
+}
+
+#[test]
+fn ws_close_sends_code_and_reason() -> err::Result<()> {
+    let (mut client, mut ws) = ws_pair();
+
+    ws.close(1000, "done")?;
+    ws.close(1000, "twice")?;
+    drop(ws);
+
+    let mut close = Vec::new();
+    client.read_to_end(&mut close)?;
+    assert_eq!(close, [0x88, 0x06, 0x03, 0xe8, b'd', b'o', b'n', b'e']);
+
+    Ok(())
+}
+
+#[test]
+fn ws_split_halves() -> err::Result<()> {
+    let (mut client, ws) = ws_pair();
+    let (mut reader, writer) = ws.split()?;
+
+    let parked = std::thread::spawn(move || {
+        let msg = reader.recv();
+        (reader, msg)
+    });
+    std::thread::sleep(std::time::Duration::from_millis(50));
+
+    writer.send_str("from writer")?;
+    let mut frame = [0u8; 13];
+    client.read_exact(&mut frame)?;
+    assert_eq!(frame[..2], [0x81, 11]);
+    assert_eq!(&frame[2..], b"from writer");
+
+    client.write_all(&client_frame(0x81, b"from client"))?;
+    let (mut reader, msg) = parked.join().unwrap();
+    match msg? {
+        Some(crate::Payload::Str(s)) => assert_eq!(s, "from client"),
+        p => panic!("unexpected payload: {:?}", p),
+    }
+
+    writer.close(1000, "")?;
+    client.write_all(&client_frame(0x88, &1000u16.to_be_bytes()))?;
+    assert!(reader.recv()?.is_none());
+    assert_eq!(reader.peer_close(), Some((1000, "")));
+    drop((reader, writer));
+
+    let mut close = Vec::new();
+    client.read_to_end(&mut close)?;
+    assert_eq!(close, [0x88, 0x02, 0x03, 0xe8]);
+
+    Ok(())
+}
+
+#[test]
+fn ws_keepalive_closes_without_pong() -> err::Result<()> {
+    let (mut client, mut ws) = ws_pair();
+    ws.set_keepalive(Some(std::time::Duration::from_millis(30)))?;
+
+    client.write_all(&client_frame(0x81, b"early"))?;
+    match ws.recv()? {
+        Some(crate::Payload::Str(s)) => assert_eq!(s, "early"),
+        p => panic!("unexpected payload: {:?}", p),
+    }
+
+    assert!(ws.recv().is_err());
+    drop(ws);
+
+    let mut frames = Vec::new();
+    client.read_to_end(&mut frames)?;
+    let mut expected = vec![0x89, 0x00, 0x88, 0x15, 0x03, 0xf3];
+    expected.extend_from_slice(b"keepalive timed out");
+    assert_eq!(frames, expected);
+
+    Ok(())
+}
+
+#[test]
+fn ws_unmask_in_place() -> err::Result<()> {
+    let payload: Vec<u8> = (0..70000u32).map(|i| (i * 31 % 256) as u8).collect();
+    let frame = client_frame(0x82, &payload);
+    let hdr = crate::FrameHeader::parse(&frame)?.unwrap();
+
+    let mut data = frame[hdr.header_len..].to_vec();
+    hdr.unmask_in_place(&mut data);
+    assert_eq!(data, payload);
+
+    let (mut client, mut ws) = ws_pair();
+    let mut frames = frame.clone();
+    frames.extend(client_frame(0x81, b"tail"));
+    client.write_all(&frames)?;
+    match ws.recv()? {
+        Some(crate::Payload::Bin(b)) => assert_eq!(b, payload),
+        p => panic!("unexpected payload: {:?}", p),
+    }
+    match ws.recv()? {
+        Some(crate::Payload::Str(s)) => assert_eq!(s, "tail"),
+        p => panic!("unexpected payload: {:?}", p),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn ws_send_wire_format() -> err::Result<()> {
+    let (mut client, mut ws) = ws_pair();
+
+    assert_eq!(ws.send_str("hi")?, 4);
+    let long = "x".repeat(300);
+    assert_eq!(ws.send_str(&long)?, 304);
+    assert_eq!(ws.send_str("")?, 2);
+    drop(ws);
+
+    let mut wire = Vec::new();
+    client.read_to_end(&mut wire)?;
+    let mut expected = vec![0x81, 0x02, b'h', b'i', 0x81, 126, 0x01, 0x2c];
+    expected.extend_from_slice(long.as_bytes());
+    expected.extend_from_slice(&[0x81, 0x00]);
+    assert_eq!(wire, expected);
+
+    Ok(())
+}
+
+#[test]
+fn ws_rejects_unmasked_frame() -> err::Result<()> {
+    let (mut client, mut ws) = ws_pair();
+
+    client.write_all(&[0x81, 0x02, b'h', b'i'])?;
+
+    match ws.recv() {
+        Err(err::Error::Input(msg)) => assert_eq!(msg, "unmasked client frame"),
+        _ => panic!("expected a protocol error"),
+    }
+
+    let mut close = [0u8; 4];
+    client.read_exact(&mut close)?;
+    assert_eq!(close, [0x88, 0x17, 0x03, 0xea]);
+
+    Ok(())
+}
+
+#[test]
+fn ws_large_binary_frame() -> err::Result<()> {
+    let (mut client, mut ws) = ws_pair();
+
+    let data: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+    let frame = client_frame(0x82, &data);
+    let writer = std::thread::spawn(move || client.write_all(&frame));
+
+    match ws.recv()? {
+        Some(crate::Payload::Bin(b)) => assert!(b == data),
+        p => panic!("unexpected payload: {:?}", p.map(|_| ())),
+    }
+    writer.join().unwrap()?;
+
+    Ok(())
+}
+
+#[test]
+fn ws_frame_split_byte_by_byte() -> err::Result<()> {
+    let (mut client, mut ws) = ws_pair();
+    client.set_nodelay(true)?;
+
+    let mut frames = client_frame(0x81, b"one byte at a time");
+    frames.extend(client_frame(0x81, b"again"));
+    let writer = std::thread::spawn(move || -> std::io::Result<TcpStream> {
+        for b in frames {
+            client.write_all(&[b])?;
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        Ok(client)
+    });
+
+    match ws.recv()? {
+        Some(crate::Payload::Str(s)) => assert_eq!(s, "one byte at a time"),
+        p => panic!("unexpected payload: {:?}", p),
+    }
+    match ws.recv()? {
+        Some(crate::Payload::Str(s)) => assert_eq!(s, "again"),
+        p => panic!("unexpected payload: {:?}", p),
+    }
+
+    drop(writer.join().unwrap()?);
+    assert!(ws.recv()?.is_none());
+
+    Ok(())
+}
+
+#[test]
+fn frame_header_64_bit_length() -> err::Result<()> {
+    let mut buf = vec![0x82, 127];
+    buf.extend_from_slice(&(u32::MAX as u64 + 2).to_be_bytes());
+
+    match crate::FrameHeader::parse(&buf) {
+        #[cfg(target_pointer_width = "64")]
+        Ok(Some(hdr)) => assert_eq!(hdr.payload_len as u64, u32::MAX as u64 + 2),
+        #[cfg(not(target_pointer_width = "64"))]
+        Err(err::Error::Input(_)) => {}
+        _ => panic!("unexpected parse result"),
+    }
+
+    buf[2] = 0x80;
+    match crate::FrameHeader::parse(&buf) {
+        Err(err::Error::Input(msg)) => assert_eq!(msg, "payload length has the high bit set"),
+        _ => panic!("expected an input error"),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn ws_rejects_huge_frame() -> err::Result<()> {
+    let (mut client, mut ws) = ws_pair();
+
+    let mut frame = vec![0x82, 0x80 | 127];
+    frame.extend_from_slice(&(1u64 << 40).to_be_bytes());
+    frame.extend_from_slice(&MASK);
+    client.write_all(&frame)?;
+
+    match ws.recv() {
+        Err(err::Error::Input(msg)) => assert_eq!(msg, "message too big"),
+        _ => panic!("expected a protocol error"),
+    }
+
+    let mut close = [0u8; 4];
+    client.read_exact(&mut close)?;
+    assert_eq!(close, [0x88, 0x11, 0x03, 0xf1]);
+
+    Ok(())
+}
+
+#[test]
+fn ws_max_message_size_counts_fragments() -> err::Result<()> {
+    let (mut client, mut ws) = ws_pair();
+    ws.set_max_message_size(8);
+
+    let mut frames = client_frame(0x01, b"12345");
+    frames.extend(client_frame(0x80, b"6789"));
+    client.write_all(&frames)?;
+
+    match ws.recv() {
+        Err(err::Error::Input(msg)) => assert_eq!(msg, "message too big"),
+        _ => panic!("expected a protocol error"),
+    }
+
+    // the limit lowered below what is already buffered
+    let (mut client, mut ws) = ws_pair();
+    client.write_all(&client_frame(0x01, b"12345"))?;
+    std::thread::sleep(std::time::Duration::from_millis(20));
+    assert!(ws.try_recv()?.is_none());
+    ws.set_max_message_size(2);
+    client.write_all(&client_frame(0x80, b"6"))?;
+    match ws.recv() {
+        Err(err::Error::Input(msg)) => assert_eq!(msg, "message too big"),
+        _ => panic!("expected a protocol error"),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn app_closure_captures_state() -> err::Result<()> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    let count = Arc::new(AtomicUsize::new(0));
+    let counter = count.clone();
+    let app = move |_req: crate::Req, client: Box<dyn crate::Stream>| {
+        let n = counter.fetch_add(1, Ordering::SeqCst) + 1;
+        crate::send_str(client, crate::Status::OK, "text/plain", &n.to_string())
+    };
+
+    for expected in ["1", "2"] {
+        let (mut client, server) = socket_pair();
+        client.write_all(b"GET / HTTP/1.1\nHost: localhost\n\n")?;
+        client.shutdown(std::net::Shutdown::Write)?;
+        crate::handle_client(server, &Default::default(), &app)?;
+
+        let mut resp = String::new();
+        client.read_to_string(&mut resp)?;
+        assert!(resp.ends_with(expected));
+    }
+    assert_eq!(count.load(Ordering::SeqCst), 2);
+
+    Ok(())
+}
+
+fn exchange(app: &impl crate::App, raw: &str) -> err::Result<String> {
+    let (mut client, server) = socket_pair();
+    client.write_all(raw.as_bytes())?;
+    client.shutdown(std::net::Shutdown::Write)?;
+    crate::handle_client(server, &Default::default(), app)?;
+
+    let mut resp = String::new();
+    client.read_to_string(&mut resp)?;
+    Ok(resp)
+}
+
+#[test]
+fn router_matches_path_and_verb() -> err::Result<()> {
+    let mut router = crate::router::Router::new();
+    router
+        .get("/users", |_, client| {
+            crate::send_str(client, crate::Status::OK, "text/plain", "list")
+        })
+        .post("/users", |req: crate::Req, client| {
+            let body = String::from_utf8(req.body)?;
+            crate::send_str(client, crate::Status::OK, "text/plain", &body)
+        });
+    let app = router.into_app();
+
+    let resp = exchange(&app, "GET /users HTTP/1.1\nHost: localhost\n\n")?;
+    assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(resp.ends_with("\r\n\r\nlist"));
+
+    let resp = exchange(
+        &app,
+        "POST /users HTTP/1.1\nHost: localhost\nContent-Length: 3\n\nbob",
+    )?;
+    assert!(resp.ends_with("\r\n\r\nbob"));
+
+    let resp = exchange(&app, "DELETE /users HTTP/1.1\nHost: localhost\n\n")?;
+    assert!(resp.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+
+    let resp = exchange(&app, "GET /nope HTTP/1.1\nHost: localhost\n\n")?;
+    assert!(resp.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+    Ok(())
+}
+
+#[test]
+fn router_path_params() -> err::Result<()> {
+    let mut router = crate::router::Router::new();
+    router
+        .get("/users/:id/posts/:post_id", |req: crate::Req, client| {
+            let body = format!(
+                "{} {}",
+                req.param("id").unwrap(),
+                req.param("post_id").unwrap()
+            );
+            crate::send_str(client, crate::Status::OK, "text/plain", &body)
+        })
+        .get("/users/:id/posts/latest", |req: crate::Req, client| {
+            let body = format!("latest for {}", req.param("id").unwrap());
+            crate::send_str(client, crate::Status::OK, "text/plain", &body)
+        });
+    let app = router.into_app();
+
+    let resp = exchange(&app, "GET /users/42/posts/7 HTTP/1.1\nHost: localhost\n\n")?;
+    assert!(resp.ends_with("\r\n\r\n42 7"));
+
+    let resp = exchange(
+        &app,
+        "GET /users/42/posts/latest HTTP/1.1\nHost: localhost\n\n",
+    )?;
+    assert!(resp.ends_with("\r\n\r\nlatest for 42"));
+
+    let resp = exchange(&app, "GET /users//posts/7 HTTP/1.1\nHost: localhost\n\n")?;
+    assert!(resp.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+    Ok(())
+}
+
+fn temp_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("webd-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn serve_dir_files() -> err::Result<()> {
+    let root = temp_dir("serve-dir");
+    std::fs::create_dir(root.join("sub"))?;
+    std::fs::write(root.join("hello.txt"), "hello")?;
+    std::fs::write(root.join("sub").join("index.html"), "<p>index</p>")?;
+    std::fs::write(root.with_extension("secret"), "secret")?;
+    let app = crate::serve_dir(&root);
+
+    let resp = exchange(&app, "GET /hello.txt HTTP/1.1\nHost: localhost\n\n")?;
+    assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(resp.contains("Content-Type: text/plain\r\n"));
+    assert!(resp.ends_with("\r\n\r\nhello"));
+
+    let resp = exchange(&app, "GET /sub/ HTTP/1.1\nHost: localhost\n\n")?;
+    assert!(resp.contains("Content-Type: text/html\r\n"));
+    assert!(resp.ends_with("\r\n\r\n<p>index</p>"));
+
+    let resp = exchange(&app, "GET /missing.txt HTTP/1.1\nHost: localhost\n\n")?;
+    assert!(resp.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+    let name = root.file_name().unwrap().to_str().unwrap();
+    for path in [
+        "/../../etc/passwd".to_string(),
+        "/%2e%2e/%2e%2e/etc/passwd".to_string(),
+        format!("/../{}.secret", name),
+        "/sub/../../etc/passwd".to_string(),
+    ] {
+        let resp = exchange(&app, &format!("GET {} HTTP/1.1\nHost: localhost\n\n", path))?;
+        assert!(resp.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", path);
+    }
+
+    std::fs::remove_dir_all(&root)?;
+    std::fs::remove_file(root.with_extension("secret"))?;
+    Ok(())
+}
+
+#[test]
+fn mime_from_extension() {
+    assert_eq!(crate::mime::from_path("logo.svg"), "image/svg+xml");
+    assert_eq!(crate::mime::from_path("/app/main.WASM"), "application/wasm");
+    assert_eq!(crate::mime::from_path("data.json"), "application/json");
+    assert_eq!(
+        crate::mime::from_path("blob.xyz"),
+        "application/octet-stream"
+    );
+    assert_eq!(
+        crate::mime::from_path("Makefile"),
+        "application/octet-stream"
+    );
+}
+
+#[test]
+fn send_file_auto_sets_content_type() -> err::Result<()> {
+    let root = temp_dir("send-file-auto");
+    let fname = root.join("logo.svg");
+    std::fs::write(&fname, "<svg/>")?;
+
+    let (mut client, server) = socket_pair();
+    crate::send_file_auto(server, crate::Status::OK, fname.to_str().unwrap())?;
+
+    let head = read_head(BufReader::new(&mut client));
+    assert!(head.iter().any(|h| h == "Content-Type: image/svg+xml"));
+
+    std::fs::remove_dir_all(&root)?;
+    Ok(())
+}
+
+#[test]
+fn response_builder_writes_headers_and_body() -> err::Result<()> {
+    let mut out = Vec::new();
+    crate::Response::new(crate::Status::OK)
+        .content_type("text/plain")
+        .header("Cache-Control", "no-store")
+        .body_str("hi there")
+        .write_to(&mut out)?;
+
+    let out = String::from_utf8(out)?;
+    let mut lines = out.lines();
+    assert_eq!(lines.next(), Some("HTTP/1.1 200 OK"));
+    assert!(lines.next().unwrap().starts_with("Date: "));
+    assert_eq!(lines.next(), Some("Content-Type: text/plain"));
+    assert_eq!(lines.next(), Some("Cache-Control: no-store"));
+    assert_eq!(lines.next(), Some("Content-Length: 8"));
+    assert_eq!(lines.next(), Some(""));
+    assert_eq!(lines.next(), Some("hi there"));
+
+    Ok(())
+}
+
+#[test]
+fn response_builder_file_and_empty_bodies() -> err::Result<()> {
+    let root = temp_dir("response-file");
+    let fname = root.join("data.bin");
+    std::fs::write(&fname, [1u8, 2, 3])?;
+
+    let mut out = Vec::new();
+    crate::Response::new(crate::Status::OK)
+        .body_file(&fname)
+        .write_to(&mut out)?;
+    assert!(out.ends_with(b"Content-Length: 3\r\n\r\n\x01\x02\x03"));
+
+    let mut out = Vec::new();
+    crate::Response::new(crate::Status::NotFound)
+        .header("Location", "/elsewhere")
+        .write_to(&mut out)?;
+    assert!(out.ends_with(b"Location: /elsewhere\r\nContent-Length: 0\r\n\r\n"));
+
+    std::fs::remove_dir_all(&root)?;
+    Ok(())
+}
+
+#[test]
+fn status_from_code() {
+    assert_eq!(crate::Status::from_code(201).to_string(), "201 Created");
+    assert_eq!(crate::Status::from_code(404), crate::Status::NotFound);
+    assert_eq!(
+        crate::Status::from_code(500).to_string(),
+        "500 Internal Server Error"
+    );
+    assert_eq!(
+        crate::Status::from_code(418).to_string(),
+        "418 Client Error"
+    );
+    assert_eq!(
+        crate::Status::from_code(599).to_string(),
+        "599 Server Error"
+    );
+    assert_eq!(crate::Status::from_code(799).to_string(), "799 ");
+    assert_eq!(
+        crate::Status::Custom(418, "I'm a teapot").to_string(),
+        "418 I'm a teapot"
+    );
+}
+
+#[test]
+fn send_str_custom_status() -> err::Result<()> {
+    let (client, server) = socket_pair();
+    crate::send_str(
+        server,
+        crate::Status::from_code(429),
+        "text/plain",
+        "slow down",
+    )?;
+
+    let head = read_head(BufReader::new(client));
+    assert_eq!(head[0], "HTTP/1.1 429 Too Many Requests");
+
+    Ok(())
+}
+
+#[test]
+fn response_http_1_1_with_date() -> err::Result<()> {
+    let mut out = Vec::new();
+    crate::Response::new(crate::Status::OK).write_to(&mut out)?;
+
+    let head = read_head(BufReader::new(&out[..]));
+    assert_eq!(head[0], "HTTP/1.1 200 OK");
+    let date = head
+        .iter()
+        .find_map(|h| h.strip_prefix("Date: "))
+        .expect("a Date header");
+    let date = crate::date::parse(date).expect("a parseable Date header");
+    let age = std::time::SystemTime::now().duration_since(date).unwrap();
+    assert!(age.as_secs() < 5);
+
+    Ok(())
+}
+
+#[test]
+fn date_format_and_parse() {
+    let t = std::time::UNIX_EPOCH + std::time::Duration::from_secs(784111777);
+    assert_eq!(crate::date::format(t), "Sun, 06 Nov 1994 08:49:37 GMT");
+    assert_eq!(crate::date::parse("Sun, 06 Nov 1994 08:49:37 GMT"), Some(t));
+
+    let leap = std::time::UNIX_EPOCH + std::time::Duration::from_secs(951782400);
+    assert_eq!(crate::date::format(leap), "Tue, 29 Feb 2000 00:00:00 GMT");
+    assert_eq!(
+        crate::date::parse("Tue, 29 Feb 2000 00:00:00 GMT"),
+        Some(leap)
+    );
+
+    assert_eq!(crate::date::parse("yesterday"), None);
+}
+
+#[test]
+fn send_headers_uses_crlf() -> err::Result<()> {
+    let (mut client, mut server) = socket_pair();
+    crate::send_headers(&mut server, crate::Status::OK, "text/plain", 0)?;
+    drop(server);
+
+    let mut raw = Vec::new();
+    client.read_to_end(&mut raw)?;
+    assert!(raw.starts_with(b"HTTP/1.1 200 OK\r\n"));
+    assert!(raw.ends_with(b"Content-Length: 0\r\n\r\n"));
+    for (i, b) in raw.iter().enumerate() {
+        if *b == b'\n' {
+            assert_eq!(raw[i - 1], b'\r');
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn keep_alive_rules() -> err::Result<()> {
+    let cases = [
+        ("GET / HTTP/1.1\nHost: localhost\n\n", true),
+        (
+            "GET / HTTP/1.1\nHost: localhost\nConnection: close\n\n",
+            false,
+        ),
+        (
+            "GET / HTTP/1.1\nHost: localhost\nConnection: Upgrade\n\n",
+            false,
+        ),
+        ("GET / HTTP/1.0\n\n", false),
+        ("GET / HTTP/1.0\nConnection: Keep-Alive\n\n", true),
+    ];
+    for (raw, expected) in cases {
+        let req = crate::Req::parse(BufReader::new(raw.as_bytes()))?;
+        assert_eq!(req.keep_alive(), expected, "{}", raw);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn keep_alive_serves_pipelined_requests() -> err::Result<()> {
+    let app = |req: crate::Req, client| {
+        crate::send_str(client, crate::Status::OK, "text/plain", &req.path)
+    };
+
+    let (mut client, server) = socket_pair();
+    client.write_all(b"GET /one HTTP/1.1\r\nHost: localhost\r\n\r\nGET /two HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")?;
+    crate::handle_client(server, &Default::default(), &app)?;
+
+    let mut resp = String::new();
+    client.read_to_string(&mut resp)?;
+    let (first, second) = resp.split_once("/one").unwrap();
+    assert!(first.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(!first.contains("Connection:"));
+    assert!(second.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(second.contains("\r\nConnection: close\r\n"));
+    assert!(second.ends_with("\r\n\r\n/two"));
+
+    // HTTP/1.0 clients are told when the connection stays open
+    let resp = exchange(
+        &app,
+        "GET /one HTTP/1.0\r\nConnection: keep-alive\r\n\r\nGET /two HTTP/1.0\r\n\r\n",
+    )?;
+    let (first, second) = resp.split_once("/one").unwrap();
+    assert!(first.contains("\r\nConnection: keep-alive\r\n"));
+    assert!(second.contains("\r\nConnection: close\r\n"));
+    assert!(second.ends_with("\r\n\r\n/two"));
+
+    // a body that runs to the close can't be followed by another response
+    let until_close = |_, mut client: Box<dyn crate::Stream>| -> err::Result<()> {
+        client.write_all(b"HTTP/1.1 200 OK\r\n\r\nall of it")?;
+        Ok(())
+    };
+    let resp = exchange(
+        &until_close,
+        "GET /one HTTP/1.1\r\nHost: localhost\r\n\r\nGET /two HTTP/1.1\r\nHost: localhost\r\n\r\n",
+    )?;
+    assert_eq!(
+        resp,
+        "HTTP/1.1 200 OK\r\nServer: webd 0.1\r\nConnection: close\r\n\r\nall of it"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn pipelined_requests_keep_their_bodies() -> err::Result<()> {
+    let app = |req: crate::Req, client| {
+        let body = format!("{} {}", req.path, String::from_utf8(req.body)?);
+        crate::send_str(client, crate::Status::OK, "text/plain", &body)
+    };
+
+    let raw = "POST /one HTTP/1.1\r\nHost: localhost\r\nContent-Length: 3\r\n\r\nabc\
+               POST /two HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n\
+               2\r\nde\r\n0\r\n\r\n\
+               GET /three HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+    let resp = exchange(&app, raw)?;
+    let bodies: Vec<&str> = resp
+        .split("HTTP/1.1 200 OK\r\n")
+        .skip(1)
+        .filter_map(|r| r.split_once("\r\n\r\n").map(|(_, body)| body))
+        .collect();
+    assert_eq!(bodies, ["/one abc", "/two de", "/three "]);
+
+    Ok(())
+}
+
+fn spawn_server(config: crate::Config, app: impl crate::App + 'static) -> std::net::SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, shutdown) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let _tx = tx;
+        crate::serve_on(listener, config, app, shutdown)
+    });
+    addr
+}
+
+fn get(addr: std::net::SocketAddr, path: &str) -> err::Result<String> {
+    let mut client = TcpStream::connect(addr)?;
+    write!(
+        client,
+        "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        path
+    )?;
+    let mut resp = String::new();
+    client.read_to_string(&mut resp)?;
+    Ok(resp)
+}
+
+#[test]
+fn pool_serves_clients_in_parallel() -> err::Result<()> {
+    let app = |req: crate::Req, client| {
+        if req.path == "/panic" {
+            panic!("handler blew up");
+        }
+        std::thread::sleep(std::time::Duration::from_millis(300));
+        crate::send_str(client, crate::Status::OK, "text/plain", "slow")
+    };
+    let config = crate::Config {
+        workers: 4,
+        ..Default::default()
+    };
+    let addr = spawn_server(config, app);
+
+    for _ in 0..4 {
+        assert!(get(addr, "/panic")?.starts_with("HTTP/1.1 500 "));
+    }
+
+    let start = std::time::Instant::now();
+    let clients: Vec<_> = (0..4)
+        .map(|_| std::thread::spawn(move || get(addr, "/")))
+        .collect();
+    for c in clients {
+        assert!(c.join().unwrap()?.ends_with("\r\n\r\nslow"));
+    }
+    assert!(start.elapsed() < std::time::Duration::from_millis(900));
+
+    Ok(())
+}
+
+#[test]
+fn serve_with_shutdown_returns() -> err::Result<()> {
+    let (tx, shutdown) = std::sync::mpsc::channel();
+    let (done_tx, done) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let app = |_, client| crate::send_str(client, crate::Status::OK, "text/plain", "");
+        let r = crate::serve_with_shutdown("127.0.0.1:0", app, shutdown);
+        done_tx.send(r.is_ok()).unwrap();
+    });
+
+    tx.send(()).unwrap();
+    let ok = done
+        .recv_timeout(std::time::Duration::from_secs(5))
+        .expect("serve did not return");
+    assert!(ok);
+
+    Ok(())
+}
+
+#[test]
+fn silent_client_times_out() -> err::Result<()> {
+    let app = |_, client| crate::send_str(client, crate::Status::OK, "text/plain", "hi");
+    let config = crate::Config {
+        workers: 1,
+        read_timeout: Some(std::time::Duration::from_millis(100)),
+        ..Default::default()
+    };
+    let addr = spawn_server(config, app);
+
+    let _silent = TcpStream::connect(addr)?;
+    std::thread::sleep(std::time::Duration::from_millis(20));
+
+    let start = std::time::Instant::now();
+    assert!(get(addr, "/")?.ends_with("\r\n\r\nhi"));
+    assert!(start.elapsed() >= std::time::Duration::from_millis(50));
+
+    Ok(())
+}
+
+#[test]
+fn idle_keep_alive_connection_is_closed() -> err::Result<()> {
+    let app = |_, client| crate::send_str(client, crate::Status::OK, "text/plain", "hi\n");
+    let config = crate::Config {
+        read_timeout: Some(std::time::Duration::from_secs(10)),
+        keep_alive_timeout: Some(std::time::Duration::from_millis(200)),
+        ..Default::default()
+    };
+    let (mut client, server) = socket_pair();
+    let served = std::thread::spawn(move || crate::handle_client(server, &config, &app));
+
+    client.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")?;
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    client.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")?;
+
+    // the wait after the second request is the idle one, not read_timeout
+    let start = std::time::Instant::now();
+    let mut resp = String::new();
+    client.read_to_string(&mut resp)?;
+    assert_eq!(resp.matches("\r\n\r\nhi\n").count(), 2);
+    assert!(start.elapsed() < std::time::Duration::from_secs(5));
+    assert!(served.join().unwrap().is_ok());
+
+    Ok(())
+}
+
+#[test]
+fn handler_errors_do_not_stop_the_server() -> err::Result<()> {
+    let app = |req: crate::Req, client| match req.path.as_str() {
+        "/fail" => err::input("handler failed".to_string()),
+        "/panic" => panic!("handler panicked"),
+        _ => crate::send_str(client, crate::Status::OK, "text/plain", "still here"),
+    };
+    let config = crate::Config {
+        workers: 1,
+        ..Default::default()
+    };
+    let addr = spawn_server(config, app);
+
+    assert!(get(addr, "/fail")?.starts_with("HTTP/1.1 500 "));
+    assert!(get(addr, "/panic")?.starts_with("HTTP/1.1 500 "));
+    assert!(get(addr, "/")?.ends_with("\r\n\r\nstill here"));
+
+    let (mut client, server) = socket_pair();
+    client.write_all(
+        b"GET /fail HTTP/1.1\r\nHost: localhost\r\n\r\nGET / HTTP/1.1\r\nHost: localhost\r\n\r\n",
+    )?;
+    crate::handle_client(server, &Default::default(), &app)?;
+    let mut resp = String::new();
+    client.read_to_string(&mut resp)?;
+    // the connection closes after the 500, so the second request is dropped
+    assert_eq!(resp.matches("HTTP/1.1 ").count(), 1);
+    assert!(resp.starts_with("HTTP/1.1 500 "), "{}", resp);
+
+    Ok(())
+}
+
+#[test]
+fn send_bytes_writes_binary_body() -> err::Result<()> {
+    let png = [0x89u8, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a, 0x00];
+    let (client, server) = socket_pair();
+    crate::send_bytes(server, crate::Status::OK, "image/png", &png)?;
+
+    let mut client = BufReader::new(client);
+    let head = read_head(&mut client);
+    assert!(head.iter().any(|h| h == "Content-Type: image/png"));
+    assert!(head.iter().any(|h| h == "Content-Length: 9"));
+
+    let mut body = Vec::new();
+    client.read_to_end(&mut body)?;
+    assert_eq!(body, png);
+
+    Ok(())
+}
+
+fn file_request(
+    fname: &std::path::Path,
+    header: Option<(&str, &str)>,
+) -> err::Result<(Vec<String>, Vec<u8>)> {
+    let mut raw = String::from("GET /file HTTP/1.1\nHost: localhost\n");
+    if let Some((name, value)) = header {
+        raw.push_str(&format!("{}: {}\n", name, value));
+    }
+    raw.push('\n');
+    let req = crate::Req::parse(BufReader::new(raw.as_bytes()))?;
+
+    let (client, server) = socket_pair();
+    crate::send_file_for(&req, server, "text/plain", fname.to_str().unwrap())?;
+
+    let mut client = BufReader::new(client);
+    let head = read_head(&mut client);
+    let mut body = Vec::new();
+    client.read_to_end(&mut body)?;
+    Ok((head, body))
+}
+
+#[test]
+fn send_file_ranges() -> err::Result<()> {
+    let root = temp_dir("send-file-ranges");
+    let fname = root.join("digits.txt");
+    std::fs::write(&fname, "0123456789")?;
+
+    let (head, body) = file_request(&fname, Some(("Range", "bytes=2-5")))?;
+    assert_eq!(head[0], "HTTP/1.1 206 Partial Content");
+    assert!(head.iter().any(|h| h == "Content-Range: bytes 2-5/10"));
+    assert!(head.iter().any(|h| h == "Content-Length: 4"));
+    assert_eq!(body, b"2345");
+
+    let (head, body) = file_request(&fname, Some(("Range", "bytes=7-")))?;
+    assert_eq!(head[0], "HTTP/1.1 206 Partial Content");
+    assert!(head.iter().any(|h| h == "Content-Range: bytes 7-9/10"));
+    assert_eq!(body, b"789");
+
+    let (head, body) = file_request(&fname, Some(("Range", "bytes=-3")))?;
+    assert!(head.iter().any(|h| h == "Content-Range: bytes 7-9/10"));
+    assert_eq!(body, b"789");
+
+    let (head, body) = file_request(&fname, Some(("Range", "bytes=20-30")))?;
+    assert_eq!(head[0], "HTTP/1.1 416 Range Not Satisfiable");
+    assert!(head.iter().any(|h| h == "Content-Range: bytes */10"));
+    assert!(body.is_empty());
+
+    let (head, body) = file_request(&fname, None)?;
+    assert_eq!(head[0], "HTTP/1.1 200 OK");
+    assert!(head.iter().any(|h| h == "Accept-Ranges: bytes"));
+    assert_eq!(body, b"0123456789");
+
+    std::fs::remove_dir_all(&root)?;
+    Ok(())
+}
+
+fn header_value<'a>(head: &'a [String], name: &str) -> Option<&'a str> {
+    let prefix = format!("{}: ", name);
+    head.iter().find_map(|h| h.strip_prefix(&prefix))
+}
+
+#[test]
+fn send_file_conditional_get() -> err::Result<()> {
+    let root = temp_dir("send-file-etag");
+    let fname = root.join("page.html");
+    std::fs::write(&fname, "<p>cached</p>")?;
+
+    let (head, body) = file_request(&fname, None)?;
+    assert_eq!(head[0], "HTTP/1.1 200 OK");
+    assert_eq!(body, b"<p>cached</p>");
+    let etag = header_value(&head, "ETag").expect("an ETag").to_string();
+    assert!(etag.starts_with("W/\""));
+    let modified = header_value(&head, "Last-Modified")
+        .expect("a Last-Modified")
+        .to_string();
+
+    let (head, body) = file_request(&fname, Some(("If-None-Match", &etag)))?;
+    assert_eq!(head[0], "HTTP/1.1 304 Not Modified");
+    assert_eq!(header_value(&head, "Content-Length"), None);
+    assert!(body.is_empty());
+
+    let (head, body) = file_request(&fname, Some(("If-None-Match", "W/\"other\"")))?;
+    assert_eq!(head[0], "HTTP/1.1 200 OK");
+    assert_eq!(body, b"<p>cached</p>");
+
+    let (head, _) = file_request(&fname, Some(("If-Modified-Since", &modified)))?;
+    assert_eq!(head[0], "HTTP/1.1 304 Not Modified");
+
+    let old = "Sun, 06 Nov 1994 08:49:37 GMT";
+    let (head, _) = file_request(&fname, Some(("If-Modified-Since", old)))?;
+    assert_eq!(head[0], "HTTP/1.1 200 OK");
+
+    std::fs::remove_dir_all(&root)?;
+    Ok(())
+}
+
+#[test]
+fn gzip_for_accepting_client() -> err::Result<()> {
+    let text = "hello hello hello hello, compressible world\n".repeat(50);
+    let raw = "GET / HTTP/1.1\nHost: localhost\nAccept-Encoding: deflate, gzip;q=0.8\n\n";
+    let req = crate::Req::parse(BufReader::new(raw.as_bytes()))?;
+
+    let (client, server) = socket_pair();
+    crate::send_str_for(&req, server, crate::Status::OK, "text/plain", &text)?;
+
+    let mut client = BufReader::new(client);
+    let head = read_head(&mut client);
+    let mut body = Vec::new();
+    client.read_to_end(&mut body)?;
+
+    assert_eq!(header_value(&head, "Content-Encoding"), Some("gzip"));
+    assert_eq!(header_value(&head, "Vary"), Some("Accept-Encoding"));
+    let len = header_value(&head, "Content-Length").unwrap();
+    assert_eq!(len.parse::<usize>().unwrap(), body.len());
+    assert!(body.len() < text.len());
+    assert_eq!(crate::deflate::gunzip(&body)?, text.as_bytes());
+
+    let raw = "GET / HTTP/1.1\nHost: localhost\nAccept-Encoding: gzip;q=0\n\n";
+    let req = crate::Req::parse(BufReader::new(raw.as_bytes()))?;
+    let (client, server) = socket_pair();
+    crate::send_str_for(&req, server, crate::Status::OK, "text/plain", &text)?;
+    let mut client = BufReader::new(client);
+    let head = read_head(&mut client);
+    assert_eq!(header_value(&head, "Content-Encoding"), None);
+
+    Ok(())
+}
+
+#[test]
+fn deflate_round_trip() -> err::Result<()> {
+    let mut data = Vec::new();
+    for i in 0..20000u32 {
+        data.extend_from_slice(format!("{} ", i % 377).as_bytes());
+        data.push((i * 7919 % 251) as u8);
+    }
+    let packed = crate::deflate::compress(&data);
+    assert!(packed.len() < data.len());
+    assert_eq!(crate::deflate::inflate(&packed)?, data);
+    assert_eq!(
+        crate::deflate::inflate(&crate::deflate::compress(b""))?,
+        b""
+    );
+    assert_eq!(crate::deflate::crc32(b"123456789"), 0xcbf43926);
+
+    // tables reused across inputs don't match against stale positions
+    let mut compressor = crate::deflate::Compressor::new();
+    for input in [&data[..5000], b"abcabcabc", &data[1000..9000], b""] {
+        let packed = compressor.compress_sync(input);
+        assert_eq!(
+            crate::deflate::inflate_sync(&packed, &[], usize::MAX)?,
+            input
+        );
+    }
+
+    // zlib output using a dynamic huffman block
+    let zlib = [
+        0x05, 0xc1, 0x01, 0x01, 0x00, 0x00, 0x00, 0x82, 0xa0, 0xad, 0xd8, 0xff, 0x0f, 0x01, 0x84,
+        0x41, 0x08, 0xc2, 0x20, 0x74,
+    ];
+    assert_eq!(
+        crate::deflate::inflate(&zlib)?,
+        b"aaaabaaacaaaabaaab".repeat(2)
+    );
+
+    Ok(())
+}
+
+#[test]
+fn chunked_response() -> err::Result<()> {
+    let (client, server) = socket_pair();
+    let mut out = crate::send_chunked(server, crate::Status::OK, "text/plain")?;
+    out.write_all(b"hello, ")?;
+    out.write_all(b"")?;
+    out.write_all(b"chunked ")?;
+    out.write_all("world".repeat(10).as_bytes())?;
+    drop(out);
+
+    let mut client = BufReader::new(client);
+    let head = read_head(&mut client);
+    assert_eq!(head[0], "HTTP/1.1 200 OK");
+    assert_eq!(header_value(&head, "Transfer-Encoding"), Some("chunked"));
+    assert_eq!(header_value(&head, "Content-Length"), None);
+
+    let mut body = Vec::new();
+    let mut chunks = 0;
+    loop {
+        let mut line = String::new();
+        client.read_line(&mut line)?;
+        let len = usize::from_str_radix(line.trim_end(), 16).unwrap();
+        let mut chunk = vec![0; len + 2];
+        client.read_exact(&mut chunk)?;
+        assert!(chunk.ends_with(b"\r\n"));
+        if len == 0 {
+            break;
+        }
+        body.extend_from_slice(&chunk[..len]);
+        chunks += 1;
+    }
+    assert_eq!(chunks, 3);
+    assert_eq!(
+        body,
+        format!("hello, chunked {}", "world".repeat(10)).as_bytes()
+    );
+
+    let mut rest = Vec::new();
+    client.read_to_end(&mut rest)?;
+    assert!(rest.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn parse_chunked_body() -> err::Result<()> {
+    let raw = "POST /upload HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n\
+               7\r\nhello, \r\n\
+               6;ext=1\r\nchunks\r\n\
+               0\r\nX-Trailer: yes\r\n\r\n\
+               GET /next HTTP/1.1\r\nHost: localhost\r\n\r\n";
+    let mut reader = BufReader::new(raw.as_bytes());
+    let req = crate::Req::parse(&mut reader)?;
+    assert_eq!(req.body, b"hello, chunks");
+    assert_eq!(req.header("X-Trailer"), None);
+
+    let next = crate::Req::parse(&mut reader)?;
+    assert_eq!(next.path, "/next");
+
+    let raw = "POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n";
+    match crate::Req::parse(BufReader::new(raw.as_bytes())) {
+        Err(err::Error::Input(_)) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("malformed chunk size accepted"),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn ambiguous_body_framing_is_refused() -> err::Result<()> {
+    let ok = |_, client| crate::send_str(client, crate::Status::OK, "text/plain", "ok\n");
+    let smuggled = "GET /smuggled HTTP/1.1\r\nHost: localhost\r\n\r\n";
+    let cases = [
+        (
+            "Transfer-Encoding: gzip\r\n",
+            "transfer coding is not chunked",
+        ),
+        (
+            "Transfer-Encoding: chunked\r\nContent-Length: 5\r\n",
+            "both transfer encoding and content length",
+        ),
+        (
+            "Content-Length: 5\r\nContent-Length: 6\r\n",
+            "conflicting content lengths",
+        ),
+        ("Content-Length: 5, 6\r\n", "conflicting content lengths"),
+        ("Content-Length: +5\r\n", "bad content length: +5"),
+    ];
+    for (headers, msg) in cases {
+        let raw = format!(
+            "POST / HTTP/1.1\r\nHost: localhost\r\n{}\r\n0\r\n\r\n{}",
+            headers, smuggled
+        );
+        match crate::Req::parse(BufReader::new(raw.as_bytes())) {
+            Err(err::Error::Input(e)) => assert_eq!(e, msg),
+            _ => panic!("accepted {:?}", headers),
+        }
+
+        // answered with 400, and nothing after it is read as a request
+        let out = String::from_utf8(crate::test_util::request(&ok, raw.as_bytes())?)?;
+        assert!(out.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", out);
+        assert!(out.contains("\r\nConnection: close\r\n"));
+        assert_eq!(out.matches("HTTP/1.1 ").count(), 1, "{}", out);
+    }
+
+    // the same length repeated is only redundant
+    let raw =
+        "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 2\r\nContent-Length: 2\r\n\r\nhi";
+    let req = crate::Req::parse(BufReader::new(raw.as_bytes()))?;
+    assert_eq!(req.body, b"hi");
+
+    Ok(())
+}
+
+#[test]
+fn parse_cookies() -> err::Result<()> {
+    let raw = "GET / HTTP/1.1\nHost: localhost\nCookie: a=1; b=two; c=\"x y\"\nCookie: a=ignored;d = 4\n\n";
+    let req = crate::Req::parse(BufReader::new(raw.as_bytes()))?;
+
+    let cookies = req.cookies();
+    assert_eq!(cookies.len(), 4);
+    assert_eq!(cookies["a"], "1");
+    assert_eq!(cookies["b"], "two");
+    assert_eq!(cookies["c"], "x y");
+    assert_eq!(req.cookie("d").as_deref(), Some("4"));
+    assert_eq!(req.cookie("missing"), None);
+
+    Ok(())
+}
+
+#[test]
+fn set_cookie_headers() -> err::Result<()> {
+    let session = crate::Cookie::new("session", "abc123")?
+        .path("/")?
+        .http_only()
+        .same_site(crate::SameSite::Lax);
+    assert_eq!(
+        session.to_string(),
+        "session=abc123; Path=/; HttpOnly; SameSite=Lax"
+    );
+
+    let theme = crate::Cookie::new("theme", "dark")?
+        .domain("example.com")?
+        .max_age(std::time::Duration::from_secs(3600))
+        .expires(std::time::UNIX_EPOCH)
+        .secure();
+    assert_eq!(
+        theme.to_string(),
+        "theme=dark; Domain=example.com; Max-Age=3600; \
+         Expires=Thu, 01 Jan 1970 00:00:00 GMT; Secure"
+    );
+
+    let mut out = Vec::new();
+    crate::Response::new(crate::Status::OK)
+        .cookie(&session)
+        .cookie(&theme)
+        .write_to(&mut out)?;
+    let out = String::from_utf8(out)?;
+    let cookies: Vec<_> = out
+        .lines()
+        .filter_map(|l| l.strip_prefix("Set-Cookie: "))
+        .collect();
+    assert_eq!(cookies, [session.to_string(), theme.to_string()]);
+
+    // nothing that could end the attribute or the header
+    assert!(crate::Cookie::new("a", "b\r\nX-Evil: 1").is_err());
+    assert!(crate::Cookie::new("a", "b; Domain=evil.example").is_err());
+    assert!(crate::Cookie::new("a b", "c").is_err());
+    assert!(crate::Cookie::new("a=b", "c").is_err());
+    assert!(crate::Cookie::new("", "c").is_err());
+    assert!(crate::Cookie::new("a", "")?.path("/\nX-Evil: 1").is_err());
+    assert!(crate::Cookie::new("a", "")?
+        .domain("example.com; Secure")
+        .is_err());
+
+    Ok(())
+}
+
+#[test]
+fn redirect_sets_location() -> err::Result<()> {
+    let (client, server) = socket_pair();
+    crate::redirect(server, crate::Status::PermanentRedirect, "/index.html")?;
+    let mut client = BufReader::new(client);
+    let head = read_head(&mut client);
+    assert_eq!(head[0], "HTTP/1.1 308 Permanent Redirect");
+    assert_eq!(header_value(&head, "Location"), Some("/index.html"));
+    assert_eq!(header_value(&head, "Content-Length"), Some("0"));
+
+    let (_client, server) = socket_pair();
+    assert!(crate::redirect(server, crate::Status::OK, "/").is_err());
+    let (_client, server) = socket_pair();
+    assert!(crate::redirect(server, crate::Status::Found, "/\r\nX-Evil: 1").is_err());
+
+    Ok(())
+}
+
+fn cors_router() -> crate::router::Router {
+    let cors = crate::cors::Cors::new()
+        .allow_origin("https://app.example")
+        .allow_methods(&[crate::Verb::Get, crate::Verb::Put])
+        .allow_headers(&["Content-Type", "Authorization"])
+        .max_age(std::time::Duration::from_secs(600));
+    let mut router = crate::router::Router::new();
+    router
+        .cors(cors)
+        .put("/items/:id", |req: crate::Req, client| {
+            crate::send_str_for(&req, client, crate::Status::OK, "text/plain", "updated\n")
+        })
+        .get("/plain", |_, client| {
+            crate::send_str(client, crate::Status::OK, "text/plain", "plain\n")
+        });
+    router
+}
+
+#[test]
+fn cors_preflight() -> err::Result<()> {
+    let app = cors_router().into_app();
+    let resp = exchange(
+        &app,
+        "OPTIONS /items/7 HTTP/1.1\r\nHost: localhost\r\nOrigin: https://app.example\r\n\
+         Access-Control-Request-Method: PUT\r\nConnection: close\r\n\r\n",
+    )?;
+    let head: Vec<String> = resp.lines().map(|l| l.to_string()).collect();
+    assert_eq!(head[0], "HTTP/1.1 204 No Content");
+    assert_eq!(
+        header_value(&head, "Access-Control-Allow-Origin"),
+        Some("https://app.example")
+    );
+    assert_eq!(
+        header_value(&head, "Access-Control-Allow-Methods"),
+        Some("GET, PUT")
+    );
+    assert_eq!(
+        header_value(&head, "Access-Control-Allow-Headers"),
+        Some("Content-Type, Authorization")
+    );
+    assert_eq!(header_value(&head, "Access-Control-Max-Age"), Some("600"));
+    assert_eq!(header_value(&head, "Content-Length"), None);
+    assert!(!resp.contains("updated"));
+
+    Ok(())
+}
+
+#[test]
+fn cors_allow_origin_on_request() -> err::Result<()> {
+    let app = cors_router().into_app();
+    let resp = exchange(
+        &app,
+        "PUT /items/7 HTTP/1.1\r\nHost: localhost\r\nOrigin: https://app.example\r\nConnection: close\r\n\r\n",
+    )?;
+    let head: Vec<String> = resp.lines().map(|l| l.to_string()).collect();
+    assert_eq!(head[0], "HTTP/1.1 200 OK");
+    assert_eq!(
+        header_value(&head, "Access-Control-Allow-Origin"),
+        Some("https://app.example")
+    );
+    assert!(resp.ends_with("updated\n"));
+
+    let resp = exchange(
+        &app,
+        "PUT /items/7 HTTP/1.1\r\nHost: localhost\r\nOrigin: https://evil.example\r\nConnection: close\r\n\r\n",
+    )?;
+    assert!(!resp.contains("Access-Control-Allow-Origin"));
+
+    // handlers that never see the request still get the headers
+    let resp = exchange(
+        &app,
+        "GET /plain HTTP/1.1\r\nHost: localhost\r\nOrigin: https://app.example\r\nConnection: close\r\n\r\n",
+    )?;
+    let head: Vec<String> = resp.lines().map(|l| l.to_string()).collect();
+    assert_eq!(
+        header_value(&head, "Access-Control-Allow-Origin"),
+        Some("https://app.example")
+    );
+    assert_eq!(header_value(&head, "Vary"), Some("Origin"));
+    assert!(resp.ends_with("\r\n\r\nplain\n"));
+
+    Ok(())
+}
+
+#[test]
+fn parse_form_body() -> err::Result<()> {
+    let raw = "POST /signup HTTP/1.1\r\nHost: localhost\r\n\
+               Content-Type: application/x-www-form-urlencoded; charset=utf-8\r\n\
+               Content-Length: 34\r\n\r\n\
+               name=Jane+Doe&age=30&note=a%26b%3D";
+    let req = crate::Req::parse(BufReader::new(raw.as_bytes()))?;
+    let form = req.form()?;
+    assert_eq!(form.len(), 3);
+    assert_eq!(form["name"], "Jane Doe");
+    assert_eq!(form["age"], "30");
+    assert_eq!(form["note"], "a&b=");
+
+    let raw = "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Type: text/plain\r\nContent-Length: 3\r\n\r\na=b";
+    let req = crate::Req::parse(BufReader::new(raw.as_bytes()))?;
+    assert!(req.form()?.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn parse_multipart_body() -> err::Result<()> {
+    let body = "preamble\r\n\
+                --XyZ\r\n\
+                Content-Disposition: form-data; name=\"title\"\r\n\r\n\
+                holiday\r\n\
+                --XyZ\r\n\
+                Content-Disposition: form-data; name=\"photo\"; filename=\"a; b.txt\"\r\n\
+                Content-Type: text/plain\r\n\r\n\
+                line one\r\n--not the boundary\r\n\
+                --XyZ--\r\n";
+    let raw = format!(
+        "POST /upload HTTP/1.1\r\nHost: localhost\r\n\
+         Content-Type: multipart/form-data; boundary=\"XyZ\"\r\n\
+         Content-Length: {}\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    let req = crate::Req::parse(BufReader::new(raw.as_bytes()))?;
+    let parts = req.multipart()?;
+    assert_eq!(parts.len(), 2);
+
+    assert_eq!(parts[0].name.as_deref(), Some("title"));
+    assert_eq!(parts[0].filename, None);
+    assert_eq!(parts[0].body, b"holiday");
+
+    assert_eq!(parts[1].name.as_deref(), Some("photo"));
+    assert_eq!(parts[1].filename.as_deref(), Some("a; b.txt"));
+    assert_eq!(parts[1].header("content-type"), Some("text/plain"));
+    assert_eq!(parts[1].body, b"line one\r\n--not the boundary");
+
+    Ok(())
+}
+
+#[test]
+fn req_remote_addr() -> err::Result<()> {
+    let (mut client, server) = socket_pair();
+    let expected = client.local_addr()?;
+    let seen = std::sync::Mutex::new(None);
+    let app = |req: crate::Req, client| {
+        *seen.lock().unwrap() = req.remote_addr;
+        crate::send_str(client, crate::Status::OK, "text/plain", "ok\n")
+    };
+
+    client.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")?;
+    crate::handle_client(server, &Default::default(), &app)?;
+    assert_eq!(*seen.lock().unwrap(), Some(expected));
+
+    let req = crate::Req::parse(BufReader::new(
+        "GET / HTTP/1.1\nHost: localhost\n\n".as_bytes(),
+    ))?;
+    assert_eq!(req.remote_addr, None);
+
+    Ok(())
+}
+
+#[test]
+fn req_client_ip() -> err::Result<()> {
+    let parse = |headers: &str| {
+        let raw = format!("GET / HTTP/1.1\nHost: localhost\n{}\n", headers);
+        let mut req = crate::Req::parse(BufReader::new(raw.as_bytes())).unwrap();
+        req.remote_addr = Some("10.0.0.1:4000".parse().unwrap());
+        req
+    };
+    let ip = |s: &str| Some(s.parse::<std::net::IpAddr>().unwrap());
+
+    let req = parse("X-Forwarded-For: 1.2.3.4, 10.0.0.1\n");
+    assert_eq!(req.client_ip(true), ip("1.2.3.4"));
+    assert_eq!(req.client_ip(false), ip("10.0.0.1"));
+
+    let req = parse("Forwarded: for=\"[2001:db8::1]:8080\";proto=https, for=10.0.0.1\n");
+    assert_eq!(req.client_ip(true), ip("2001:db8::1"));
+
+    let req = parse("X-Forwarded-For: not-an-ip\n");
+    assert_eq!(req.client_ip(true), ip("10.0.0.1"));
+
+    Ok(())
+}
+
+#[test]
+fn logger_sees_each_request() -> err::Result<()> {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let seen = events.clone();
+    let logger: crate::Logger = Arc::new(move |event: &crate::LogEvent| {
+        if let crate::LogEvent::Request(r) = event {
+            let status = r.status.map(|s| s.code());
+            seen.lock()
+                .unwrap()
+                .push((r.verb, r.path.to_string(), status));
+        }
+    });
+    let app = |req: crate::Req, client| match req.path.as_str() {
+        "/" => crate::send_str(client, crate::Status::OK, "text/plain", "home\n"),
+        _ => crate::send_str(client, crate::Status::NotFound, "text/plain", "nope\n"),
+    };
+    let config = crate::Config {
+        logger: Some(logger),
+        ..Default::default()
+    };
+    let addr = spawn_server(config, app);
+
+    get(addr, "/")?;
+    get(addr, "/missing")?;
+
+    let events = events.lock().unwrap();
+    assert_eq!(
+        *events,
+        [
+            (crate::Verb::Get, "/".to_string(), Some(200)),
+            (crate::Verb::Get, "/missing".to_string(), Some(404)),
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn logger_records_timing_and_size() -> err::Result<()> {
+    let logged = Arc::new(Mutex::new(None));
+    let seen = logged.clone();
+    let logger: crate::Logger = Arc::new(move |event: &crate::LogEvent| {
+        if let crate::LogEvent::Request(r) = event {
+            *seen.lock().unwrap() = Some((r.duration, r.bytes));
+        }
+    });
+    let app = |_, client| {
+        std::thread::sleep(std::time::Duration::from_millis(25));
+        crate::send_str(client, crate::Status::OK, "text/plain", "slept\n")
+    };
+    let config = crate::Config {
+        logger: Some(logger),
+        ..Default::default()
+    };
+    let addr = spawn_server(config, app);
+
+    let resp = get(addr, "/")?;
+    let (duration, bytes) = logged.lock().unwrap().unwrap();
+    assert!(duration >= std::time::Duration::from_millis(25));
+    assert_eq!(bytes, Some(resp.len() as u64));
+
+    Ok(())
+}
+
+#[derive(Clone)]
+struct MemStream {
+    input: Arc<Mutex<std::io::Cursor<Vec<u8>>>>,
+    output: Arc<Mutex<Vec<u8>>>,
+    // the size of each buffer read into
+    reads: Arc<Mutex<Vec<usize>>>,
+}
+
+impl MemStream {
+    fn new(input: &[u8]) -> MemStream {
+        MemStream {
+            input: Arc::new(Mutex::new(std::io::Cursor::new(input.to_vec()))),
+            output: Arc::new(Mutex::new(Vec::new())),
+            reads: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+}
+
+impl Read for MemStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.reads.lock().unwrap().push(buf.len());
+        self.input.lock().unwrap().read(buf)
+    }
+}
+
+impl Write for MemStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.output.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl crate::Stream for MemStream {
+    fn try_clone(&self) -> std::io::Result<Box<dyn crate::Stream>> {
+        Ok(Box::new(self.clone()))
+    }
+
+    fn set_read_timeout(&self, _timeout: Option<std::time::Duration>) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn stream_defaults_report_unsupported() -> err::Result<()> {
+    struct Bare(std::io::Cursor<Vec<u8>>);
+    impl Read for Bare {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+    impl Write for Bare {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    impl crate::Stream for Bare {
+        fn try_clone(&self) -> std::io::Result<Box<dyn crate::Stream>> {
+            Ok(Box::new(Bare(self.0.clone())))
+        }
+    }
+
+    use crate::Stream;
+    let bare = Bare(std::io::Cursor::new(Vec::new()));
+    let unsupported =
+        |r: std::io::Result<()>| r.is_err_and(|e| e.kind() == std::io::ErrorKind::Unsupported);
+    assert!(unsupported(
+        bare.set_read_timeout(Some(std::time::Duration::from_secs(1)))
+    ));
+    assert!(unsupported(bare.shutdown()));
+    bare.set_read_timeout(None)?;
+
+    // so a server that wants a read timeout says it can't have one
+    let raw = b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n".to_vec();
+    let app = |_, client| crate::send_str(client, crate::Status::OK, "text/plain", "hi\n");
+    let result = crate::handle_client(Bare(std::io::Cursor::new(raw)), &Default::default(), &app);
+    match result {
+        Err(err::Error::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::Unsupported),
+        r => panic!(
+            "expected the timeout to be refused, got {:?}",
+            r.map(|_| ())
+        ),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn handler_over_memory_stream() -> err::Result<()> {
+    let mut router = crate::router::Router::new();
+    router.get("/hello/:name", |req: crate::Req, client| {
+        let msg = format!("hello, {}\n", req.param("name").unwrap());
+        crate::send_str(client, crate::Status::OK, "text/plain", &msg)
+    });
+    let app = router.into_app();
+
+    let stream = MemStream::new(
+        b"GET /hello/mem HTTP/1.1\r\nHost: localhost\r\n\r\nGET /nope HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    );
+    crate::handle_client(stream.clone(), &Default::default(), &app)?;
+
+    let out = String::from_utf8(stream.output.lock().unwrap().clone())?;
+    let (first, second) = out.split_at(out.find("hello, mem\n").unwrap() + 11);
+    assert!(first.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(second.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    assert!(second.ends_with("not found\n"));
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn serve_over_unix_socket() -> err::Result<()> {
+    use std::os::unix::net::{UnixListener, UnixStream};
+
+    let path = temp_dir("unix-socket").join("webd.sock");
+    // leave a stale socket file behind, as a crashed server would
+    drop(UnixListener::bind(&path)?);
+    assert!(path.exists());
+
+    let app = |req: crate::Req, client| {
+        let msg = format!("unix {}\n", req.path);
+        crate::send_str(client, crate::Status::OK, "text/plain", &msg)
+    };
+    let get = |path: &std::path::Path| -> err::Result<String> {
+        let mut client = None;
+        for _ in 0..100 {
+            match UnixStream::connect(path) {
+                Ok(c) => {
+                    client = Some(c);
+                    break;
+                }
+                Err(_) => std::thread::sleep(std::time::Duration::from_millis(10)),
+            }
+        }
+        let mut client = client.expect("server never bound the socket");
+        client.write_all(b"GET /ping HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")?;
+        let mut resp = String::new();
+        client.read_to_string(&mut resp)?;
+        Ok(resp)
+    };
+
+    let server_path = path.clone();
+    std::thread::spawn(move || crate::serve_unix(server_path, app));
+    let resp = get(&path)?;
+    assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(resp.ends_with("unix /ping\n"));
+
+    // the builder takes the same settings as a TCP server
+    let path = temp_dir("unix-socket-builder").join("webd.sock");
+    let server = crate::Server::bind_unix(&path).server_name(Some("local/1.0"));
+    std::thread::spawn(move || server.run(app));
+    let resp = get(&path)?;
+    assert!(resp.contains("\r\nServer: local/1.0\r\n"), "{}", resp);
+    assert!(resp.ends_with("unix /ping\n"));
+
+    Ok(())
+}
+
+// Stands in for a TLS session: a one byte "handshake" picks the key that
+// everything after it is XORed with.
+struct XorStream {
+    inner: Box<dyn crate::Stream>,
+    key: u8,
+}
+
+impl Read for XorStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        buf[..n].iter_mut().for_each(|b| *b ^= self.key);
+        Ok(n)
+    }
+}
+
+impl Write for XorStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let data: Vec<u8> = buf.iter().map(|b| b ^ self.key).collect();
+        self.inner.write_all(&data)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl crate::Stream for XorStream {
+    fn try_clone(&self) -> std::io::Result<Box<dyn crate::Stream>> {
+        Ok(Box::new(XorStream {
+            inner: self.inner.try_clone()?,
+            key: self.key,
+        }))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<std::time::Duration>) -> std::io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+}
+
+#[test]
+fn serve_wrapped_streams() -> err::Result<()> {
+    let app = |req: crate::Req, client| {
+        let msg = format!("wrapped {}\n", req.path);
+        crate::send_str(client, crate::Status::OK, "text/plain", &msg)
+    };
+    let config = crate::Config {
+        wrap_stream: Some(Arc::new(|mut inner: Box<dyn crate::Stream>| {
+            let mut key = [0u8];
+            inner.read_exact(&mut key)?;
+            Ok(Box::new(XorStream { inner, key: key[0] }) as Box<dyn crate::Stream>)
+        })),
+        ..Default::default()
+    };
+    let addr = spawn_server(config, app);
+
+    let key = 0x5a;
+    let mut client = TcpStream::connect(addr)?;
+    let req = b"GET /secret HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+    let mut sent = vec![key];
+    sent.extend(req.iter().map(|b| b ^ key));
+    client.write_all(&sent)?;
+    let mut resp = Vec::new();
+    client.read_to_end(&mut resp)?;
+    let resp: Vec<u8> = resp.iter().map(|b| b ^ key).collect();
+    let resp = String::from_utf8(resp)?;
+    assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"), "{}", resp);
+    assert!(resp.ends_with("wrapped /secret\n"));
+
+    Ok(())
+}
+
+#[test]
+fn configurable_server_header() -> err::Result<()> {
+    let app = |_, client| crate::send_str(client, crate::Status::OK, "text/plain", "hi\n");
+
+    let config = crate::Config {
+        server_name: Some("custom/2.0".to_string()),
+        ..Default::default()
+    };
+    let resp = get(spawn_server(config, app), "/")?;
+    assert!(resp.contains("\r\nServer: custom/2.0\r\n"));
+
+    let config = crate::Config {
+        server_name: None,
+        ..Default::default()
+    };
+    let resp = get(spawn_server(config, app), "/")?;
+    assert!(!resp.contains("Server:"));
+
+    // a response's own Server header is left alone
+    let own = |_, mut client: Box<dyn crate::Stream>| {
+        crate::Response::new(crate::Status::OK)
+            .server_name(Some("per-response"))
+            .write_to(&mut client)
+    };
+    let resp = exchange(&own, "GET / HTTP/1.0\r\n\r\n")?;
+    assert!(resp.contains("\r\nServer: per-response\r\n"));
+    assert_eq!(resp.matches("Server:").count(), 1);
+
+    // and one written anywhere but a connection gets none
+    let mut out = Vec::new();
+    crate::Response::new(crate::Status::OK).write_to(&mut out)?;
+    assert!(!String::from_utf8(out)?.contains("Server:"));
+
+    Ok(())
+}
+
+#[test]
+fn server_builder_config() -> err::Result<()> {
+    let server = crate::Server::new("127.0.0.1:0");
+    let config = server.config();
+    assert!(config.workers > 0);
+    assert_eq!(
+        config.read_timeout,
+        Some(std::time::Duration::from_secs(30))
+    );
+    assert_eq!(config.server_name.as_deref(), Some("webd 0.1"));
+    assert_eq!(config.limits.max_headers, 100);
+    assert!(config.keep_alive);
+    assert_eq!(
+        config.keep_alive_timeout,
+        Some(std::time::Duration::from_secs(5))
+    );
+    assert!(config.logger.is_none());
+
+    let server = server
+        .workers(2)
+        .read_timeout(None)
+        .keep_alive_timeout(Some(std::time::Duration::from_secs(1)))
+        .server_name(None)
+        .keep_alive(false)
+        .logger(|_| {});
+    let config = server.config();
+    assert_eq!(config.workers, 2);
+    assert_eq!(config.read_timeout, None);
+    assert_eq!(
+        config.keep_alive_timeout,
+        Some(std::time::Duration::from_secs(1))
+    );
+    assert_eq!(config.server_name, None);
+    assert!(config.logger.is_some());
+
+    let app = |_, client| crate::send_str(client, crate::Status::OK, "text/plain", "once\n");
+    let stream = MemStream::new(
+        b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\nGET / HTTP/1.1\r\nHost: localhost\r\n\r\n",
+    );
+    crate::handle_client(stream.clone(), config, &app)?;
+    let out = String::from_utf8(stream.output.lock().unwrap().clone())?;
+    assert_eq!(out.matches("once\n").count(), 1);
+
+    Ok(())
+}
+
+#[test]
+fn send_file_to_client_that_hangs_up() -> err::Result<()> {
+    let root = temp_dir("send-file-hangup");
+    let fname = root.join("big.bin");
+    std::fs::write(&fname, vec![7u8; 32 * 1024 * 1024])?;
+
+    let (mut client, server) = socket_pair();
+    let reader = std::thread::spawn(move || {
+        let mut start = [0u8; 1024];
+        client.read_exact(&mut start).unwrap();
+    });
+    let sent = crate::send_file(
+        server,
+        crate::Status::OK,
+        "application/octet-stream",
+        fname.to_str().unwrap(),
+    );
+    reader.join().unwrap();
+    match sent {
+        Err(e) => assert!(e.is_disconnect(), "unexpected error: {}", e),
+        Ok(_) => panic!("send to a closed client succeeded"),
+    }
+
+    let (mut client, server) = socket_pair();
+    match crate::send_file(
+        server,
+        crate::Status::OK,
+        "text/plain",
+        root.to_str().unwrap(),
+    ) {
+        Err(err::Error::Input(msg)) => assert!(msg.starts_with("not a regular file")),
+        _ => panic!("expected a directory to be refused"),
+    }
+    let mut rest = Vec::new();
+    client.read_to_end(&mut rest)?;
+    assert!(rest.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn head_omits_body() -> err::Result<()> {
+    let root = temp_dir("head-request");
+    let fname = root.join("page.html");
+    std::fs::write(&fname, "<p>twenty-one bytes</p>")?;
+    let len = std::fs::metadata(&fname)?.len();
+
+    let mut router = crate::router::Router::new();
+    router.get("/page", move |req: crate::Req, client| {
+        crate::send_file_for(&req, client, "text/html", fname.to_str().unwrap())
+    });
+    let app = router.into_app();
+
+    let resp = exchange(
+        &app,
+        "HEAD /page HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    )?;
+    let head: Vec<String> = resp.lines().map(|l| l.to_string()).collect();
+    assert_eq!(head[0], "HTTP/1.1 200 OK");
+    assert_eq!(
+        header_value(&head, "Content-Length"),
+        Some(&*len.to_string())
+    );
+    assert!(resp.ends_with("\r\n\r\n"));
+
+    let resp = exchange(
+        &app,
+        "GET /page HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    )?;
+    assert!(resp.ends_with("<p>twenty-one bytes</p>"));
+
+    // the connection stays usable after HEAD, with nothing of the body left
+    // to be read as the next response
+    let resp = exchange(
+        &app,
+        "HEAD /page HTTP/1.1\r\nHost: localhost\r\n\r\n\
+         GET /page HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    )?;
+    let (first, second) = resp.split_once("\r\n\r\n").unwrap();
+    let head: Vec<String> = first.lines().map(|l| l.to_string()).collect();
+    assert_eq!(
+        header_value(&head, "Content-Length"),
+        Some(&*len.to_string())
+    );
+    assert!(second.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(second.ends_with("\r\n\r\n<p>twenty-one bytes</p>"));
+
+    Ok(())
+}
+
+#[test]
+fn router_options_lists_allowed_methods() -> err::Result<()> {
+    let ok = |_, client| crate::send_str(client, crate::Status::OK, "text/plain", "ok\n");
+    let mut router = crate::router::Router::new();
+    router.get("/x", ok).post("/x", ok).delete("/y", ok);
+    let app = router.into_app();
+
+    let allow = |resp: &str| -> Vec<String> {
+        let head: Vec<String> = resp.lines().map(|l| l.to_string()).collect();
+        let mut verbs: Vec<String> = header_value(&head, "Allow")
+            .unwrap_or("")
+            .split(", ")
+            .map(|v| v.to_string())
+            .collect();
+        verbs.sort();
+        verbs
+    };
+
+    let resp = exchange(
+        &app,
+        "OPTIONS /x HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    )?;
+    assert!(resp.starts_with("HTTP/1.1 204 No Content\r\n"));
+    assert_eq!(allow(&resp), ["GET", "HEAD", "OPTIONS", "POST"]);
+    assert!(!resp.contains("Content-Length"), "{}", resp);
+
+    let resp = exchange(
+        &app,
+        "PUT /x HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    )?;
+    assert!(resp.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+    assert_eq!(allow(&resp), ["GET", "HEAD", "OPTIONS", "POST"]);
+    assert!(resp.ends_with("method not allowed\n"));
+
+    let resp = exchange(
+        &app,
+        "OPTIONS * HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    )?;
+    assert_eq!(allow(&resp), ["DELETE", "GET", "HEAD", "OPTIONS", "POST"]);
+
+    let resp = exchange(
+        &app,
+        "OPTIONS /z HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    )?;
+    assert!(resp.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+    Ok(())
+}
+
+#[test]
+fn req_path_segments() -> err::Result<()> {
+    let parse = |target: &str| {
+        let raw = format!("GET {} HTTP/1.1\nHost: localhost\n\n", target);
+        crate::Req::parse(BufReader::new(raw.as_bytes())).unwrap()
+    };
+
+    assert_eq!(parse("/a/b/c/").path_segments(), ["a", "b", "c"]);
+    assert_eq!(parse("//a//b%20c?x=1").path_segments(), ["a", "b c"]);
+    assert!(parse("/").path_segments().is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn parse_rejects_bad_version() -> err::Result<()> {
+    for line in ["GET / BOGUS", "GET / HTTP/2", "GET / http/1.1"] {
+        let raw = format!("{}\n\n", line);
+        match crate::Req::parse(BufReader::new(raw.as_bytes())) {
+            Err(err::Error::Input(msg)) => assert!(msg.starts_with("unsupported version")),
+            _ => panic!("accepted {:?}", line),
+        }
+    }
+    let req = crate::Req::parse(BufReader::new("GET / HTTP/1.0\n\n".as_bytes()))?;
+    assert_eq!(req.version, "HTTP/1.0");
+
+    Ok(())
+}
+
+#[test]
+fn parse_rejects_extra_request_line_tokens() -> err::Result<()> {
+    for line in ["GET /a b HTTP/1.1", "GET / HTTP/1.1 extra"] {
+        let raw = format!("{}\n\n", line);
+        match crate::Req::parse(BufReader::new(raw.as_bytes())) {
+            Err(err::Error::Input(msg)) => assert!(msg.starts_with("malformed request line")),
+            _ => panic!("accepted {:?}", line),
+        }
+    }
+
+    let stream = MemStream::new(b"GET /a b HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    let app = |_: crate::Req, _: Box<dyn crate::Stream>| -> err::Result<()> {
+        panic!("handler called for a malformed request")
+    };
+    crate::handle_client(stream.clone(), &Default::default(), &app)?;
+    let out = String::from_utf8(stream.output.lock().unwrap().clone())?;
+    assert!(out.contains(" 400 "), "{}", out);
+
+    Ok(())
+}
+
+#[test]
+fn unanswered_requests_get_not_found() -> err::Result<()> {
+    let mut router = crate::router::Router::new();
+    router.get("/here", |_, client| {
+        crate::send_str(client, crate::Status::OK, "text/plain", "here\n")
+    });
+    let app = router.into_app();
+    let stream =
+        MemStream::new(b"GET /missing HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    crate::handle_client(stream.clone(), &Default::default(), &app)?;
+    let out = String::from_utf8(stream.output.lock().unwrap().clone())?;
+    assert!(out.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", out);
+    assert!(out.ends_with("not found\n"));
+
+    let config = crate::Config {
+        on_not_found: Some(Arc::new(|req: &crate::Req| {
+            crate::Response::new(crate::Status::NotFound)
+                .content_type("application/json")
+                .body_str(&format!("{{\"missing\":{:?}}}", req.path))
+        })),
+        ..Default::default()
+    };
+    let stream =
+        MemStream::new(b"GET /missing HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    crate::handle_client(stream.clone(), &config, &app)?;
+    let out = String::from_utf8(stream.output.lock().unwrap().clone())?;
+    assert!(out.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", out);
+    assert!(out.contains("Content-Type: application/json\r\n"));
+    assert!(out.ends_with("{\"missing\":\"/missing\"}"));
+
+    // a handler that writes nothing gets the same, and the connection
+    // carries on
+    let silent = |_, _| Ok(());
+    let stream = MemStream::new(
+        b"GET /a HTTP/1.1\r\nHost: localhost\r\n\r\nGET /b HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    );
+    crate::handle_client(stream.clone(), &Default::default(), &silent)?;
+    let out = String::from_utf8(stream.output.lock().unwrap().clone())?;
+    assert_eq!(
+        out.matches("HTTP/1.1 404 Not Found\r\n").count(),
+        2,
+        "{}",
+        out
+    );
+    assert!(out.ends_with("not found\n"));
+
+    // but one that hands the connection to another thread answers late
+    let late = |_, client: Box<dyn crate::Stream>| {
+        std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            crate::send_str(client, crate::Status::OK, "text/plain", "late\n")
+        });
+        Ok(())
+    };
+    let resp = exchange(
+        &late,
+        "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    )?;
+    assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"), "{}", resp);
+    assert_eq!(resp.matches("HTTP/1.1 ").count(), 1, "{}", resp);
+    assert!(resp.ends_with("\r\n\r\nlate\n"));
+
+    Ok(())
+}
+
+#[test]
+fn custom_error_pages() -> err::Result<()> {
+    let config = crate::Config {
+        on_bad_request: Some(Arc::new(|e: &err::Error| {
+            let msg = match e {
+                err::Error::Input(msg) => msg.as_str(),
+                _ => "",
+            };
+            crate::Response::new(crate::Status::BadRequest)
+                .content_type("application/json")
+                .body_str(&format!("{{\"error\":{:?}}}", msg))
+        })),
+        on_not_found: Some(Arc::new(|req: &crate::Req| {
+            crate::Response::new(crate::Status::NotFound)
+                .content_type("text/html")
+                .body_str(&format!("<h1>no {}</h1>", req.path))
+        })),
+        on_error: Some(Arc::new(|_: &err::Error| {
+            crate::Response::new(crate::Status::InternalServerError).body_str("oops")
+        })),
+        ..Default::default()
+    };
+    let mut router = crate::router::Router::new();
+    router.get("/fail", |_, _| err::input("broken".to_string()));
+    router.get("/panic", |_, _| panic!("broken"));
+    let app = router.into_app();
+
+    let stream = MemStream::new(b"GET / BOGUS\r\n\r\n");
+    crate::handle_client(stream.clone(), &config, &app)?;
+    let out = String::from_utf8(stream.output.lock().unwrap().clone())?;
+    assert!(out.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", out);
+    assert!(out.contains("Content-Type: application/json\r\n"));
+    assert!(
+        out.ends_with("{\"error\":\"unsupported version: BOGUS\"}"),
+        "{}",
+        out
+    );
+
+    let stream =
+        MemStream::new(b"GET /nope HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    crate::handle_client(stream.clone(), &config, &app)?;
+    let out = String::from_utf8(stream.output.lock().unwrap().clone())?;
+    assert!(out.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", out);
+    assert!(out.ends_with("<h1>no /nope</h1>"));
+
+    // serve_dir leaves its misses to the server's page too
+    let root = temp_dir("error-pages");
+    let files = crate::serve_dir(&root);
+    let stream =
+        MemStream::new(b"GET /gone.txt HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    crate::handle_client(stream.clone(), &config, &files)?;
+    let out = String::from_utf8(stream.output.lock().unwrap().clone())?;
+    assert!(out.ends_with("<h1>no /gone.txt</h1>"), "{}", out);
+    let stream =
+        MemStream::new(b"GET /gone.txt HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    crate::handle_client(stream.clone(), &Default::default(), &files)?;
+    let out = String::from_utf8(stream.output.lock().unwrap().clone())?;
+    assert!(out.ends_with("\r\n\r\nnot found\n"), "{}", out);
+    std::fs::remove_dir_all(&root)?;
+
+    let stream = MemStream::new(b"GET /fail HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    crate::handle_client(stream.clone(), &config, &app)?;
+    let out = String::from_utf8(stream.output.lock().unwrap().clone())?;
+    assert!(out.starts_with("HTTP/1.1 500 "), "{}", out);
+    assert!(out.ends_with("oops"));
+
+    let stream = MemStream::new(b"GET /panic HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    crate::handle_client(stream.clone(), &config, &app)?;
+    let out = String::from_utf8(stream.output.lock().unwrap().clone())?;
+    assert!(out.starts_with("HTTP/1.1 500 "), "{}", out);
+    assert!(out.ends_with("oops"));
+
+    Ok(())
+}
+
+#[test]
+fn handler_error_sends_500() -> err::Result<()> {
+    let app = |req: crate::Req, client| match req.path.as_str() {
+        "/early" => err::input("broken".to_string()),
+        "/panic" => panic!("broken"),
+        "/late-panic" => {
+            crate::send_str(client, crate::Status::OK, "text/plain", "partial")?;
+            panic!("broken")
+        }
+        _ => {
+            crate::send_str(client, crate::Status::OK, "text/plain", "partial")?;
+            err::input("broken".to_string())
+        }
+    };
+
+    let resp = exchange(&app, "GET /early HTTP/1.1\r\nHost: localhost\r\n\r\n")?;
+    assert!(resp.starts_with("HTTP/1.1 500 "), "{}", resp);
+    assert!(
+        resp.ends_with("\r\n\r\ninternal server error\n"),
+        "{}",
+        resp
+    );
+
+    // once the handler has responded there is nothing more to send
+    let resp = exchange(&app, "GET /late HTTP/1.1\r\nHost: localhost\r\n\r\n")?;
+    assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"), "{}", resp);
+    assert!(!resp.contains(" 500 "), "{}", resp);
+
+    // a panic gets the same treatment as an error
+    let resp = exchange(&app, "GET /panic HTTP/1.1\r\nHost: localhost\r\n\r\n")?;
+    assert!(resp.starts_with("HTTP/1.1 500 "), "{}", resp);
+    assert!(
+        resp.ends_with("\r\n\r\ninternal server error\n"),
+        "{}",
+        resp
+    );
+    let resp = exchange(&app, "GET /late-panic HTTP/1.1\r\nHost: localhost\r\n\r\n")?;
+    assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"), "{}", resp);
+    assert!(!resp.contains(" 500 "), "{}", resp);
+
+    Ok(())
+}
+
+#[test]
+fn second_response_head_is_rejected() -> err::Result<()> {
+    let results = Arc::new(Mutex::new(Vec::new()));
+    let seen = results.clone();
+    let app = move |_, mut client: Box<dyn crate::Stream>| {
+        use crate::Status;
+        crate::send_headers(&mut client, Status::OK, "text/plain", 2)?;
+        let second = crate::send_headers(&mut client, Status::OK, "text/plain", 2);
+        let third = crate::send_str(&mut client, Status::NotFound, "text/plain", "");
+        client.write_all(b"ok")?;
+
+        // nor do other threads get a second go
+        let mut clone = client.try_clone()?;
+        let late = std::thread::spawn(move || {
+            crate::send_str(&mut clone, Status::OK, "text/plain", "late")
+        });
+        let late = late.join().unwrap();
+        seen.lock().unwrap().extend([second, third, late]);
+        Ok(())
+    };
+
+    let resp = exchange(
+        &app,
+        "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    )?;
+    assert_eq!(resp.matches("HTTP/1.1 ").count(), 1, "{}", resp);
+    assert!(resp.ends_with("\r\n\r\nok"), "{}", resp);
+    let results = results.lock().unwrap();
+    assert_eq!(results.len(), 3);
+    for r in results.iter() {
+        match r {
+            Err(err::Error::Io(e)) => assert_eq!(e.to_string(), "response already sent: 200 OK"),
+            r => panic!("second head was not rejected: {:?}", r),
+        }
+    }
+
+    // a body that looks like a head is still just a body
+    let app = |_, client| {
+        let body = b"HTTP/1.1 200 OK\r\n\r\n";
+        crate::send_bytes(client, crate::Status::OK, "message/http", body)
+    };
+    let resp = exchange(
+        &app,
+        "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    )?;
+    assert!(resp.contains("\r\nContent-Length: 19\r\n"), "{}", resp);
+    assert!(
+        resp.ends_with("\r\n\r\nHTTP/1.1 200 OK\r\n\r\n"),
+        "{}",
+        resp
+    );
+
+    Ok(())
+}
+
+#[test]
+fn writes_past_the_response_are_rejected() -> err::Result<()> {
+    let rejected = Arc::new(Mutex::new(Vec::new()));
+    let seen = rejected.clone();
+    let app = move |req: crate::Req, mut client: Box<dyn crate::Stream>| {
+        if req.path == "/short" {
+            crate::send_headers(&mut client, crate::Status::OK, "text/plain", 5)?;
+            client.write_all(b"ab")?;
+        } else {
+            crate::send_headers(&mut client, crate::Status::OK, "text/plain", 2)?;
+            client.write_all(b"ok")?;
+            seen.lock()
+                .unwrap()
+                .push(client.write_all(b"more").is_err());
+        }
+        Ok(())
+    };
+
+    // a body cut short leaves the connection unusable for another response
+    let resp = exchange(
+        &app,
+        "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n\
+         GET /short HTTP/1.1\r\nHost: localhost\r\n\r\n\
+         GET / HTTP/1.1\r\nHost: localhost\r\n\r\n",
+    )?;
+    assert_eq!(resp.matches("HTTP/1.1 200 OK\r\n").count(), 2, "{}", resp);
+    assert!(resp.ends_with("\r\n\r\nab"), "{}", resp);
+    assert!(!resp.contains("more"), "{}", resp);
+    assert_eq!(*rejected.lock().unwrap(), vec![true]);
+
+    Ok(())
+}
+
+#[test]
+fn expect_continue() -> err::Result<()> {
+    let (mut client, server) = socket_pair();
+    let handle = std::thread::spawn(move || {
+        let config = crate::Config {
+            on_expect: Some(Arc::new(|req: &crate::Req| {
+                let len: usize = req.header("Content-Length")?.parse().ok()?;
+                (len > 16).then(|| {
+                    crate::Response::new(crate::Status::PayloadTooLarge).body_str("too big\n")
+                })
+            })),
+            ..Default::default()
+        };
+        let app = |req: crate::Req, client| {
+            let msg = format!("got {}\n", String::from_utf8(req.body)?);
+            crate::send_str(client, crate::Status::OK, "text/plain", &msg)
+        };
+        crate::handle_client(server, &config, &app)
+    });
+
+    client.write_all(b"POST /up HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\nExpect: 100-continue\r\n\r\n")?;
+    let mut reader = BufReader::new(client.try_clone()?);
+    assert_eq!(read_head(&mut reader), vec!["HTTP/1.1 100 Continue"]);
+    client.write_all(b"hello")?;
+    let head = read_head(&mut reader);
+    assert_eq!(head[0], "HTTP/1.1 200 OK");
+    let mut body = [0u8; 10];
+    reader.read_exact(&mut body)?;
+    assert_eq!(&body, b"got hello\n");
+
+    client
+        .write_all(b"POST /up HTTP/1.1\r\nHost: localhost\r\nContent-Length: 500\r\nExpect: 100-continue\r\n\r\n")?;
+    let head = read_head(&mut reader);
+    assert_eq!(head[0], "HTTP/1.1 413 Payload Too Large");
+    let mut rest = String::new();
+    reader.read_to_string(&mut rest)?;
+    assert_eq!(rest, "too big\n");
+    handle.join().unwrap()?;
+
+    Ok(())
+}
+
+#[test]
+fn body_size_limit() -> err::Result<()> {
+    let raw = "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 999999999\r\n\r\n";
+    match crate::Req::parse(BufReader::new(raw.as_bytes())) {
+        Err(err::Error::TooLarge(_)) => {}
+        _ => panic!("huge body accepted"),
+    }
+
+    let limits = crate::Limits {
+        max_body_size: 8,
+        ..Default::default()
+    };
+    let raw = "POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n5\r\nworld\r\n0\r\n\r\n";
+    match crate::Req::parse_with_limits(BufReader::new(raw.as_bytes()), &limits) {
+        Err(err::Error::TooLarge(_)) => {}
+        _ => panic!("chunked body grew past the limit"),
+    }
+
+    // a chunk size that would overflow once added to the body so far
+    let raw = "POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n1\r\na\r\nffffffffffffffff\r\n";
+    match crate::Req::parse(BufReader::new(raw.as_bytes())) {
+        Err(err::Error::TooLarge(_)) => {}
+        _ => panic!("overflowing chunk size accepted"),
+    }
+
+    let stream =
+        MemStream::new(b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 999999999\r\n\r\n");
+    crate::handle_client(stream.clone(), &Default::default(), &|_, _| Ok(()))?;
+    let out = String::from_utf8(stream.output.lock().unwrap().clone())?;
+    assert!(
+        out.starts_with("HTTP/1.1 413 Payload Too Large\r\n"),
+        "{}",
+        out
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_util_request() -> err::Result<()> {
+    let mut router = crate::router::Router::new();
+    router.get("/hello/:name", |req: crate::Req, client| {
+        let msg = format!("hello, {}\n", req.param("name").unwrap());
+        crate::send_str(client, crate::Status::OK, "text/plain", &msg)
+    });
+    let app = router.into_app();
+
+    let out = crate::test_util::request(
+        &app,
+        b"GET /hello/test HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    )?;
+    let out = String::from_utf8(out)?;
+    assert!(out.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(out.ends_with("\r\n\r\nhello, test\n"));
+
+    Ok(())
+}
+
+#[test]
+fn parse_response() -> err::Result<()> {
+    let mut out = Vec::new();
+    crate::send_str(
+        &mut out,
+        crate::Status::NotFound,
+        "text/plain",
+        "not here\n",
+    )?;
+    let resp = crate::Response::parse(BufReader::new(out.as_slice()))?;
+    assert_eq!(resp.version(), "HTTP/1.1");
+    assert_eq!(resp.status(), crate::Status::NotFound);
+    assert_eq!(resp.header_value("content-type"), Some("text/plain"));
+    assert_eq!(resp.header_value("Content-Length"), Some("9"));
+    assert_eq!(resp.body(), b"not here\n");
+
+    let mut out = Vec::new();
+    let mut w = crate::Response::new(crate::Status::OK).chunked(&mut out)?;
+    w.write_all(b"one ")?;
+    w.write_all(b"two")?;
+    w.finish()?;
+    let resp = crate::Response::parse(BufReader::new(out.as_slice()))?;
+    assert_eq!(resp.version(), "HTTP/1.1");
+    assert_eq!(resp.body(), b"one two");
+
+    assert!(crate::Response::parse(BufReader::new(&b"HTTP/1.1 abc\r\n\r\n"[..])).is_err());
+
+    let limits = crate::Limits {
+        max_body_size: 4,
+        ..Default::default()
+    };
+    for raw in [
+        &b"HTTP/1.1 200 OK\r\nContent-Length: 99999999999\r\n\r\n"[..],
+        b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n",
+        b"HTTP/1.0 200 OK\r\n\r\nhello",
+    ] {
+        match crate::Response::parse_with_limits(BufReader::new(raw), &limits) {
+            Err(err::Error::TooLarge(_)) => {}
+            _ => panic!("response body grew past the limit"),
+        }
+    }
+    let resp = crate::Response::parse_with_limits(
+        BufReader::new(&b"HTTP/1.0 200 OK\r\n\r\nhell"[..]),
+        &limits,
+    )?;
+    assert_eq!(resp.body(), b"hell");
+
+    Ok(())
+}
+
+#[test]
+fn ws_fragments_large_messages() -> err::Result<()> {
+    let (mut client, mut ws) = ws_pair();
+    let payload: Vec<u8> = (0..200 * 1024).map(|i| (i % 251) as u8).collect();
+    let sent = payload.clone();
+    let sender = std::thread::spawn(move || -> err::Result<usize> {
+        ws.set_fragment_size(Some(64 * 1024));
+        let n = ws.send_bin(&sent)?;
+        ws.close(1000, "")?;
+        Ok(n)
+    });
+
+    let mut raw = Vec::new();
+    client.read_to_end(&mut raw)?;
+    assert_eq!(sender.join().unwrap()?, 200 * 1024 + 3 * 10 + 4);
+
+    let mut frames = Vec::new();
+    let mut message = Vec::new();
+    let mut rest = raw.as_slice();
+    while let Some(hdr) = crate::FrameHeader::parse(rest)? {
+        frames.push((hdr.opcode.as_byte(), hdr.fin, hdr.payload_len));
+        if !hdr.opcode.is_control() {
+            message.extend_from_slice(&rest[hdr.header_len..hdr.frame_len()]);
+        }
+        rest = &rest[hdr.frame_len()..];
+    }
+    assert_eq!(
+        frames,
+        vec![
+            (0x2, false, 64 * 1024),
+            (0x0, false, 64 * 1024),
+            (0x0, false, 64 * 1024),
+            (0x0, true, 8 * 1024),
+            (0x8, true, 2),
+        ]
+    );
+    assert_eq!(message, payload);
+
+    Ok(())
+}
+
+#[test]
+fn ws_permessage_deflate() -> err::Result<()> {
+    let raw = "GET /ws HTTP/1.1\nHost: localhost\n\
+               Upgrade: websocket\n\
+               Connection: Upgrade\n\
+               Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\n\
+               Sec-WebSocket-Version: 13\n\
+               Sec-WebSocket-Extensions: permessage-deflate; server_max_window_bits=10, \
+               permessage-deflate; client_no_context_takeover\n\n";
+
+    // off unless asked for
+    let req = crate::Req::parse(BufReader::new(raw.as_bytes()))?;
+    let (client, server) = socket_pair();
+    let ws = match crate::ws_upgrade(req, Box::new(server)) {
+        crate::WsUpgrade::Success(ws) => ws,
+        _ => panic!("expected the upgrade to succeed"),
+    };
+    drop(ws);
+    let head = read_head(&mut BufReader::new(client));
+    assert_eq!(header_value(&head, "Sec-WebSocket-Extensions"), None);
+
+    let config = crate::WsConfig {
+        permessage_deflate: true,
+        ..Default::default()
+    };
+    let req = crate::Req::parse(BufReader::new(raw.as_bytes()))?;
+    let (mut client, server) = socket_pair();
+    let mut ws = match crate::ws_upgrade_with(req, Box::new(server), &config) {
+        crate::WsUpgrade::Success(ws) => ws,
+        _ => panic!("expected the upgrade to succeed"),
+    };
+    let mut reader = BufReader::new(client.try_clone()?);
+    let head = read_head(&mut reader);
+    assert_eq!(
+        header_value(&head, "Sec-WebSocket-Extensions"),
+        Some("permessage-deflate; server_no_context_takeover; client_no_context_takeover")
+    );
+
+    let msg = "{\"event\":\"tick\",\"data\":[1,2,3]} ".repeat(20);
+    let compressed = crate::deflate::compress_sync(msg.as_bytes());
+    let compressed = &compressed[..compressed.len() - 4];
+    client.write_all(&client_frame(0xc1, compressed))?;
+    match ws.recv()? {
+        Some(crate::Payload::Str(s)) => assert_eq!(s, msg),
+        p => panic!("unexpected payload: {:?}", p),
+    }
+
+    ws.send_str(&msg)?;
+    ws.send_str("hi")?;
+    let mut frame = [0u8; 4];
+    reader.read_exact(&mut frame[..2])?;
+    assert_eq!(frame[0], 0xc1);
+    let mut payload = vec![0u8; (frame[1] & 0x7f) as usize];
+    assert!(payload.len() < 126);
+    reader.read_exact(&mut payload)?;
+    payload.extend_from_slice(&[0, 0, 0xff, 0xff]);
+    let inflated = crate::deflate::inflate_sync(&payload, &[], usize::MAX)?;
+    assert_eq!(inflated, msg.as_bytes());
+
+    // not worth compressing, so sent as is
+    reader.read_exact(&mut frame)?;
+    assert_eq!(frame, [0x81, 0x02, b'h', b'i']);
+
+    // later messages reuse the compressor and still come out whole
+    ws.send_str(&msg)?;
+    reader.read_exact(&mut frame[..2])?;
+    assert_eq!(frame[0], 0xc1);
+    let mut payload = vec![0u8; (frame[1] & 0x7f) as usize];
+    reader.read_exact(&mut payload)?;
+    payload.extend_from_slice(&[0, 0, 0xff, 0xff]);
+    let inflated = crate::deflate::inflate_sync(&payload, &[], usize::MAX)?;
+    assert_eq!(inflated, msg.as_bytes());
+
+    // a protocol error rather than bad UTF-8
+    client.write_all(&client_frame(0xc1, &[0xff; 4]))?;
+    match ws.recv() {
+        Err(err::Error::Input(msg)) => assert_eq!(msg, "bad compressed data"),
+        r => panic!("expected a protocol error: {:?}", r),
+    }
+    let mut close = [0u8; 23];
+    reader.read_exact(&mut close)?;
+    assert_eq!(close[..4], [0x88, 0x15, 0x03, 0xea]);
+
+    Ok(())
+}
+
+#[test]
+fn ws_rejects_reserved_bits() -> err::Result<()> {
+    for first in [0xa1, 0xc1] {
+        let (mut client, mut ws) = ws_pair();
+        client.write_all(&client_frame(first, b"hi"))?;
+
+        match ws.recv() {
+            Err(err::Error::Input(msg)) => assert_eq!(msg, "unexpected reserved bits"),
+            _ => panic!("expected a protocol error"),
+        }
+
+        let mut close = [0u8; 4];
+        client.read_exact(&mut close)?;
+        assert_eq!(close, [0x88, 0x1a, 0x03, 0xea]);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn ws_rejects_bad_control_frames() -> err::Result<()> {
+    let cases: [(u8, &[u8], &str); 2] = [
+        (0x89, &[b'x'; 126], "control frame too long"),
+        (0x09, b"hi", "fragmented control frame"),
+    ];
+    for (first, payload, expected) in cases {
+        let (mut client, mut ws) = ws_pair();
+        client.write_all(&client_frame(first, payload))?;
+
+        match ws.recv() {
+            Err(err::Error::Input(msg)) => assert_eq!(msg, expected),
+            _ => panic!("expected a protocol error"),
+        }
+
+        let mut close = vec![0u8; 4 + expected.len()];
+        client.read_exact(&mut close)?;
+        assert_eq!(close[..4], [0x88, 2 + expected.len() as u8, 0x03, 0xea]);
+        assert_eq!(&close[4..], expected.as_bytes());
+    }
+
+    Ok(())
+}
+
+fn assert_ws_protocol_error(frames: &[u8], expected: &str) -> err::Result<()> {
+    let (mut client, mut ws) = ws_pair();
+    client.write_all(frames)?;
+
+    match ws.recv() {
+        Err(err::Error::Input(msg)) => assert_eq!(msg, expected),
+        _ => panic!("expected a protocol error"),
+    }
+    assert!(!ws.is_open());
+
+    let mut close = vec![0u8; 4 + expected.len()];
+    client.read_exact(&mut close)?;
+    assert_eq!(close[..4], [0x88, 2 + expected.len() as u8, 0x03, 0xea]);
+    assert_eq!(&close[4..], expected.as_bytes());
+    Ok(())
+}
+
+#[test]
+fn ws_rejects_stray_continuation() -> err::Result<()> {
+    assert_ws_protocol_error(
+        &client_frame(0x80, b"stray"),
+        "continuation without a message",
+    )
+}
+
+#[test]
+fn ws_rejects_nested_fragments() -> err::Result<()> {
+    let nested = "new message inside a fragmented one";
+
+    let mut frames = client_frame(0x01, b"one");
+    frames.extend(client_frame(0x01, b"two"));
+    assert_ws_protocol_error(&frames, nested)?;
+
+    let mut frames = client_frame(0x02, b"one");
+    frames.extend(client_frame(0x81, b"two"));
+    assert_ws_protocol_error(&frames, nested)?;
+
+    Ok(())
+}
+
+#[test]
+fn ws_rejects_invalid_utf8() -> err::Result<()> {
+    let reason = "invalid utf-8 in text message";
+    let cases: [&[(u8, &[u8])]; 3] = [
+        &[(0x81, b"ok \xff")],
+        // split in the middle of a valid sequence, then broken
+        &[(0x01, b"caf\xc3"), (0x00, b"\xa9 \xc3("), (0x80, b"more")],
+        &[(0x01, b"\xc3"), (0x80, b"")],
+    ];
+    for frames in cases {
+        let (mut client, mut ws) = ws_pair();
+        for (first, payload) in frames {
+            client.write_all(&client_frame(*first, payload))?;
+        }
+
+        match ws.recv() {
+            Err(err::Error::Utf8(msg)) => assert_eq!(msg, reason),
+            p => panic!("expected a utf-8 error, got {:?}", p.map(|_| ())),
+        }
+
+        let mut close = vec![0u8; 4 + reason.len()];
+        client.read_exact(&mut close)?;
+        assert_eq!(close[..4], [0x88, 2 + reason.len() as u8, 0x03, 0xef]);
+    }
+
+    // a character split across fragments is fine
+    let (mut client, mut ws) = ws_pair();
+    client.write_all(&client_frame(0x01, b"caf\xc3"))?;
+    client.write_all(&client_frame(0x80, b"\xa9"))?;
+    match ws.recv()? {
+        Some(crate::Payload::Str(s)) => assert_eq!(s, "café"),
+        p => panic!("unexpected payload: {:?}", p),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn utf8_errors_have_their_own_variant() -> err::Result<()> {
+    let e: err::Error = String::from_utf8(vec![b'a', 0xff]).unwrap_err().into();
+    match &e {
+        err::Error::Utf8(msg) => assert!(msg.contains("invalid utf-8"), "{}", msg),
+        _ => panic!("expected a utf-8 error, got {:?}", e),
+    }
+    assert_eq!(e.kind(), None);
+    assert!(e.to_string().starts_with("Error::Utf8("));
+
+    Ok(())
+}
+
+#[test]
+fn ws_is_open_until_close() -> err::Result<()> {
+    let (mut client, mut ws) = ws_pair();
+    assert!(ws.is_open());
+
+    let mut frames = client_frame(0x81, b"hi");
+    frames.extend(client_frame(0x88, &[0x03, 0xe8]));
+    client.write_all(&frames)?;
+
+    assert!(ws.recv()?.is_some());
+    assert!(ws.is_open());
+    assert!(ws.recv()?.is_none());
+    assert!(!ws.is_open());
+    assert_eq!(ws.peer_close(), Some((1000, "")));
+
+    Ok(())
+}
+
+#[test]
+fn ws_try_recv() -> err::Result<()> {
+    let (mut client, mut ws) = ws_pair();
+    assert!(ws.try_recv()?.is_none());
+    assert!(ws.is_open());
+
+    // half a frame is held on to until the rest arrives
+    let frame = client_frame(0x81, b"hello");
+    client.write_all(&frame[..4])?;
+    std::thread::sleep(std::time::Duration::from_millis(20));
+    assert!(ws.try_recv()?.is_none());
+    client.write_all(&frame[4..])?;
+
+    let mut received = None;
+    for _ in 0..100 {
+        received = ws.try_recv()?;
+        if received.is_some() {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(5));
+    }
+    match received {
+        Some(crate::Payload::Str(s)) => assert_eq!(s, "hello"),
+        p => panic!("unexpected payload: {:?}", p),
+    }
+    assert!(ws.is_open());
+
+    Ok(())
+}
+
+#[test]
+fn req_raw_headers_keep_order() -> err::Result<()> {
+    let raw =
+        "GET / HTTP/1.1\r\nX-Zeta: 1\r\nhost: example.com\r\nAccept: */*\r\nX-Zeta: 2\r\n\r\n";
+    let req = crate::Req::parse(BufReader::new(raw.as_bytes()))?;
+    let names: Vec<_> = req
+        .raw_headers()
+        .iter()
+        .map(|(n, v)| (n.as_str(), v.as_str()))
+        .collect();
+    assert_eq!(
+        names,
+        vec![
+            ("X-Zeta", "1"),
+            ("host", "example.com"),
+            ("Accept", "*/*"),
+            ("X-Zeta", "2"),
+        ]
+    );
+    assert_eq!(req.header("x-zeta"), Some("1"));
+
+    Ok(())
+}
+
+#[test]
+fn req_basic_auth() -> err::Result<()> {
+    let parse = |auth: &str| {
+        let raw = format!(
+            "GET / HTTP/1.1\r\nHost: localhost\r\nAuthorization: {}\r\n\r\n",
+            auth
+        );
+        crate::Req::parse(BufReader::new(raw.as_bytes())).unwrap()
+    };
+    assert_eq!(
+        parse("Basic QWxhZGRpbjpvcGVuIHNlc2FtZQ==").basic_auth(),
+        Some(("Aladdin".to_string(), "open sesame".to_string()))
+    );
+    assert_eq!(
+        parse("basic dXNlcjpwYTpzcw==").basic_auth(),
+        Some(("user".to_string(), "pa:ss".to_string()))
+    );
+    assert_eq!(parse("Basic not-base64!").basic_auth(), None);
+    assert_eq!(parse("Basic dXNlcg==").basic_auth(), None);
+    assert_eq!(parse("Bearer abc").basic_auth(), None);
+
+    let mut out = Vec::new();
+    crate::unauthorized(&mut out, "admin area")?;
+    let resp = crate::Response::parse(BufReader::new(out.as_slice()))?;
+    assert_eq!(resp.status(), crate::Status::Unauthorized);
+    assert_eq!(
+        resp.header_value("WWW-Authenticate"),
+        Some("Basic realm=\"admin area\"")
+    );
+    assert!(crate::unauthorized(Vec::new(), "bad\"realm").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn req_bearer_token() -> err::Result<()> {
+    let parse = |auth: &str| {
+        let raw = format!(
+            "GET / HTTP/1.1\r\nHost: localhost\r\nAuthorization: {}\r\n\r\n",
+            auth
+        );
+        crate::Req::parse(BufReader::new(raw.as_bytes())).unwrap()
+    };
+    assert_eq!(
+        parse("Bearer abc.def-123").bearer_token(),
+        Some("abc.def-123")
+    );
+    assert_eq!(parse("bearer   tok  ").bearer_token(), Some("tok"));
+    assert_eq!(parse("Bearer").bearer_token(), None);
+    assert_eq!(parse("Basic dXNlcjpwYXNz").bearer_token(), None);
+    let req = crate::Req::parse(BufReader::new(
+        "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n".as_bytes(),
+    ))?;
+    assert_eq!(req.bearer_token(), None);
+
+    Ok(())
+}
+
+#[test]
+fn parse_media_type() -> err::Result<()> {
+    let raw = "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Type: multipart/form-data; boundary=abc123\r\n\r\n";
+    let req = crate::Req::parse(BufReader::new(raw.as_bytes()))?;
+    let media = req.content_type().unwrap();
+    assert_eq!(media.essence, "multipart/form-data");
+    assert_eq!(media.param("boundary"), Some("abc123"));
+    assert_eq!(media.params.len(), 1);
+
+    let media = crate::MediaType::parse("Text/HTML; Charset=\"utf-8\"; q=\"a;b\"").unwrap();
+    assert_eq!(media.essence, "text/html");
+    assert_eq!(media.param("charset"), Some("utf-8"));
+    assert_eq!(media.param("q"), Some("a;b"));
+
+    assert_eq!(crate::MediaType::parse("nonsense"), None);
+    assert_eq!(crate::MediaType::parse("text/; x=y"), None);
+
+    Ok(())
+}
+
+#[test]
+fn http_1_1_requires_host() -> err::Result<()> {
+    for raw in ["GET / HTTP/1.1\r\n\r\n", "GET / HTTP/1.1\r\nHost:\r\n\r\n"] {
+        match crate::Req::parse(BufReader::new(raw.as_bytes())) {
+            Err(err::Error::Input(msg)) => assert_eq!(msg, "missing host header"),
+            _ => panic!("accepted {:?}", raw),
+        }
+    }
+
+    let req = crate::Req::parse(BufReader::new("GET / HTTP/1.0\r\n\r\n".as_bytes()))?;
+    assert_eq!(req.host(), None);
+    let raw = "GET / HTTP/1.1\r\nHost: example.com:8080\r\n\r\n";
+    let req = crate::Req::parse(BufReader::new(raw.as_bytes()))?;
+    assert_eq!(req.host(), Some("example.com:8080"));
+
+    let out = crate::test_util::request(&|_, _| Ok(()), b"GET / HTTP/1.1\r\n\r\n")?;
+    assert!(out.starts_with(b"HTTP/1.1 400 Bad Request\r\n"));
+    assert!(out.windows(19).any(|w| w == b"Connection: close\r\n"));
+
+    Ok(())
+}
+
+#[test]
+fn send_file_over_socket_matches_generic_copy() -> err::Result<()> {
+    let root = temp_dir("sendfile");
+    let fname = root.join("data.bin");
+    let data: Vec<u8> = (0..3 * 1024 * 1024 + 17).map(|i| (i % 253) as u8).collect();
+    std::fs::write(&fname, &data)?;
+    let fname = fname.to_str().unwrap().to_string();
+
+    for range in [None, Some("bytes=1000-2000000")] {
+        let raw = match range {
+            Some(r) => format!("GET / HTTP/1.1\r\nHost: localhost\r\nRange: {}\r\n\r\n", r),
+            None => "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n".to_string(),
+        };
+        let req = crate::Req::parse(BufReader::new(raw.as_bytes()))?;
+        let response = || {
+            crate::Response::new(crate::Status::OK)
+                .body_file(&fname)
+                .for_request(&req)
+        };
+
+        let mut generic = Vec::new();
+        response().write_to(&mut generic)?;
+
+        let (mut client, mut server) = socket_pair();
+        let reader = std::thread::spawn(move || {
+            let mut out = Vec::new();
+            client.read_to_end(&mut out).map(|_| out)
+        });
+        response().write_to_stream(&mut server)?;
+        drop(server);
+        let streamed = reader.join().unwrap()?;
+
+        let generic = crate::Response::parse(BufReader::new(generic.as_slice()))?;
+        let streamed = crate::Response::parse(BufReader::new(streamed.as_slice()))?;
+        assert_eq!(generic.status(), streamed.status());
+        assert_eq!(generic.body(), streamed.body());
+        match range {
+            Some(_) => assert_eq!(streamed.body(), &data[1000..=2000000]),
+            None => assert_eq!(streamed.body(), data.as_slice()),
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn stream_request_body() -> err::Result<()> {
+    let config = crate::Config {
+        stream_body_above: Some(64 * 1024),
+        ..Default::default()
+    };
+    let app = |req: crate::Req, client| {
+        let buffered = req.body.len();
+        let mut body = req.body_reader();
+        let mut sum = 0u64;
+        let mut buf = [0u8; 4096];
+        let mut total = 0;
+        loop {
+            let n = body.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            sum += buf[..n].iter().map(|b| *b as u64).sum::<u64>();
+            total += n;
+        }
+        let msg = format!("{} {} {}", buffered, total, sum);
+        crate::send_str(client, crate::Status::OK, "text/plain", &msg)
+    };
+
+    let data: Vec<u8> = (0..1024 * 1024).map(|i| (i % 199) as u8).collect();
+    let sum: u64 = data.iter().map(|b| *b as u64).sum();
+    let mut raw = format!(
+        "POST /up HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n",
+        data.len()
+    )
+    .into_bytes();
+    raw.extend_from_slice(&data);
+    // the connection ends with a streamed body, so this is never answered
+    raw.extend_from_slice(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    let out = crate::test_util::request_with(&config, &app, &raw)?;
+    let resp = crate::Response::parse(BufReader::new(out.as_slice()))?;
+    assert_eq!(resp.body(), format!("0 {} {}", data.len(), sum).as_bytes());
+    assert_eq!(out.windows(9).filter(|w| w == b"HTTP/1.1 ").count(), 1);
+
+    let raw = b"POST /up HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n\
+                3\r\nabc\r\n2;x=y\r\nde\r\n0\r\nTrailer: 1\r\n\r\n";
+    let out = crate::test_util::request_with(&config, &app, raw)?;
+    let resp = crate::Response::parse(BufReader::new(out.as_slice()))?;
+    assert_eq!(
+        resp.body(),
+        format!("0 5 {}", b"abcde".iter().map(|b| *b as u64).sum::<u64>()).as_bytes()
+    );
+
+    // small bodies are still buffered
+    let raw = b"POST /up HTTP/1.1\r\nHost: localhost\r\nContent-Length: 3\r\n\r\nxyz";
+    let out = crate::test_util::request_with(&config, &app, raw)?;
+    let resp = crate::Response::parse(BufReader::new(out.as_slice()))?;
+    assert_eq!(resp.body(), format!("3 3 {}", 120 + 121 + 122).as_bytes());
+
+    // streamed bodies are held to the body size limit too
+    let config = crate::Config {
+        stream_body_above: Some(0),
+        limits: crate::Limits {
+            max_body_size: 4,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let app = |req: crate::Req, client| {
+        let msg = match req.body_reader().read_to_end(&mut Vec::new()) {
+            Ok(n) => format!("read {} bytes", n),
+            Err(e) => e.to_string(),
+        };
+        crate::send_str(client, crate::Status::OK, "text/plain", &msg)
+    };
+    let raw = b"POST /up HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n\
+                3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n";
+    let out = crate::test_util::request_with(&config, &app, raw)?;
+    let resp = crate::Response::parse(BufReader::new(out.as_slice()))?;
+    assert!(String::from_utf8_lossy(resp.body()).contains("body longer than 4 bytes"));
+    let raw = b"POST /up HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nabcde";
+    let out = crate::test_util::request_with(&config, &app, raw)?;
+    let resp = crate::Response::parse(BufReader::new(out.as_slice()))?;
+    assert!(String::from_utf8_lossy(resp.body()).contains("body longer than 4 bytes"));
+
+    Ok(())
+}
+
+#[test]
+fn client_dropped_mid_headers_is_ignored() -> err::Result<()> {
+    let errors = Arc::new(Mutex::new(Vec::new()));
+    let seen = errors.clone();
+    let logger: crate::Logger = Arc::new(move |event: &crate::LogEvent| {
+        if let crate::LogEvent::Error(msg) = event {
+            seen.lock().unwrap().push(msg.clone());
+        }
+    });
+    let config = crate::Config {
+        logger: Some(logger),
+        ..Default::default()
+    };
+    let app = |_req: crate::Req, _client| -> err::Result<()> { panic!("handler ran") };
+
+    for partial in [&b"GET / HTTP/1.1\r\nHost: x\r\n"[..], b"GET / HT"] {
+        let (mut client, server) = socket_pair();
+        client.write_all(partial)?;
+        drop(client);
+        crate::handle_client(server, &config, &app)?;
+    }
+
+    assert!(
+        errors.lock().unwrap().is_empty(),
+        "{:?}",
+        errors.lock().unwrap()
+    );
+
+    Ok(())
+}
+
+#[test]
+fn ws_messages_iterates_until_close() -> err::Result<()> {
+    let (mut client, mut ws) = ws_pair();
+
+    let mut frames = client_frame(0x81, b"one");
+    frames.extend(client_frame(0x82, b"two"));
+    frames.extend(client_frame(0x88, &1000u16.to_be_bytes()));
+    client.write_all(&frames)?;
+
+    let mut got = Vec::new();
+    for msg in ws.messages() {
+        got.push(msg?);
+    }
+    match &got[..] {
+        [crate::Payload::Str(a), crate::Payload::Bin(b)] => {
+            assert_eq!(a, "one");
+            assert_eq!(b, b"two");
+        }
+        p => panic!("unexpected payloads: {:?}", p),
+    }
+    assert!(!ws.is_open());
+    assert!(ws.messages().next().is_none());
+
+    let (mut client, mut ws) = ws_pair();
+    client.write_all(&[0x81, 0x02, b'h', b'i'])?;
+    let mut msgs = ws.messages();
+    assert!(matches!(msgs.next(), Some(Err(_))));
+    assert!(msgs.next().is_none());
+
+    Ok(())
+}
+
+#[test]
+fn ws_keeps_upgrade_request() -> err::Result<()> {
+    let raw = "GET /chat?room=7 HTTP/1.1\nHost: localhost\n\
+               Upgrade: websocket\n\
+               Connection: Upgrade\n\
+               Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\n\
+               Sec-WebSocket-Version: 13\n\
+               Cookie: token=abc\n\n";
+    let req = crate::Req::parse(BufReader::new(raw.as_bytes()))?;
+    let (_client, server) = socket_pair();
+    let ws = match crate::ws_upgrade(req, Box::new(server)) {
+        crate::WsUpgrade::Success(ws) => ws,
+        _ => panic!("expected the upgrade to succeed"),
+    };
+    assert_eq!(ws.request().path, "/chat");
+    assert_eq!(ws.request().query_param("room"), Some("7"));
+    assert_eq!(ws.request().header("Cookie"), Some("token=abc"));
+
+    let (reader, _writer) = ws.split()?;
+    assert_eq!(reader.request().path, "/chat");
+
+    Ok(())
+}
+
+#[test]
+fn drain_timeout_cuts_off_hung_handlers() -> err::Result<()> {
+    let (started_tx, started) = std::sync::mpsc::channel();
+    let (ended_tx, ended) = std::sync::mpsc::channel();
+    let started_tx = Mutex::new(started_tx);
+    let ended_tx = Mutex::new(ended_tx);
+    // waits on the client, which never sends anything more
+    let app = move |_, mut client: Box<dyn crate::Stream>| {
+        started_tx.lock().unwrap().send(()).unwrap();
+        let mut buf = [0u8; 16];
+        while let Ok(n) = client.read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+        }
+        ended_tx.lock().unwrap().send(()).unwrap();
+        Ok(())
+    };
+    let config = crate::Config {
+        drain_timeout: Some(std::time::Duration::from_millis(100)),
+        ..Default::default()
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let (tx, shutdown) = std::sync::mpsc::channel();
+    let (done_tx, done) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let r = crate::serve_on(listener, config, app, shutdown);
+        done_tx.send(r.is_ok()).unwrap();
+    });
+
+    let mut client = TcpStream::connect(addr)?;
+    client.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")?;
+    started
+        .recv_timeout(std::time::Duration::from_secs(5))
+        .expect("handler did not start");
+
+    tx.send(()).unwrap();
+    let ok = done
+        .recv_timeout(std::time::Duration::from_secs(5))
+        .expect("serve did not return");
+    assert!(ok);
+    ended
+        .recv_timeout(std::time::Duration::from_secs(5))
+        .expect("handler was not cut off");
+
+    let mut rest = Vec::new();
+    client.read_to_end(&mut rest)?;
+    assert!(rest.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn ws_send_raw_skips_framing() -> err::Result<()> {
+    let (mut client, mut ws) = ws_pair();
+    let pong = [0x8a, 0x02, b'h', b'i'];
+    ws.send_raw(&pong)?;
+
+    let mut got = [0u8; 4];
+    client.read_exact(&mut got)?;
+    assert_eq!(got, pong);
+
+    let (_reader, writer) = ws.split()?;
+    writer.send_raw(&pong[..2])?;
+    let mut got = [0u8; 2];
+    client.read_exact(&mut got)?;
+    assert_eq!(got, pong[..2]);
+
+    Ok(())
+}
+
+#[test]
+fn read_buffer_size_is_used() -> err::Result<()> {
+    let mut raw = b"GET /ws HTTP/1.1\r\nHost: localhost\r\n\
+                    Upgrade: websocket\r\n\
+                    Connection: Upgrade\r\n\
+                    Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                    Sec-WebSocket-Version: 13\r\n\r\n"
+        .to_vec();
+    raw.extend(client_frame(0x82, &[9u8; 50_000]));
+    let stream = MemStream::new(&raw);
+    let reads = stream.reads.clone();
+
+    // the handler's findings come back here, where a failure fails the test
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let app = {
+        let received = received.clone();
+        move |req, client| match crate::ws_upgrade(req, client) {
+            crate::WsUpgrade::Success(mut ws) => {
+                let payload = ws.recv()?;
+                received.lock().unwrap().push(payload);
+                Ok(())
+            }
+            _ => err::input("expected the upgrade to succeed".to_string()),
+        }
+    };
+    let config = crate::Config {
+        read_buffer_size: 100_000,
+        ..Default::default()
+    };
+    crate::handle_client(stream, &config, &app)?;
+
+    // the frame came with the request, so the server's first read holds it
+    // all and the handler must be given what was read past the head
+    assert_eq!(reads.lock().unwrap().first(), Some(&100_000));
+    assert!(reads.lock().unwrap().iter().all(|&n| n == 100_000));
+
+    let received = received.lock().unwrap();
+    assert_eq!(received.len(), 1);
+    for payload in received.iter() {
+        match payload {
+            Some(crate::Payload::Bin(b)) => assert_eq!(b, &vec![9u8; 50_000]),
+            p => panic!("unexpected payload: {:?}", p),
+        }
+    }
+
+    Ok(())
+}